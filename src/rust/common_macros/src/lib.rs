@@ -352,7 +352,16 @@ macro_rules! plural(
 
 #[macro_export]
 macro_rules! get_base_dir_and_args(
-    ($args:ident, $config_path:expr) => {{
+    ($args:ident, $config_path:expr) => {
+        get_base_dir_and_args!($args, $config_path, |_: &Args| {
+            let subscriber = tracing_subscriber::fmt()
+                .with_env_filter(tracing_subscriber::EnvFilter::from_default_env())
+                .finish();
+            tracing::subscriber::set_global_default(subscriber).expect("setting default subscriber failed");
+        })
+    };
+    // `$init_tracing: fn(&Args)` installs global subscriber once args are parsed and .env is loaded
+    ($args:ident, $config_path:expr, $init_tracing:expr) => {{
         fn get_base_dir_and_args() -> Result<Option<(Option<std::path::PathBuf>, Args)>> {
             let initial_dir = std::env::current_dir().ok();
             let $args = Args::parse();
@@ -400,10 +409,7 @@ macro_rules! get_base_dir_and_args(
                 }
             }
             // pretty_env_logger::init_timed();
-            let subscriber = tracing_subscriber::fmt()
-                .with_env_filter(tracing_subscriber::EnvFilter::from_default_env())
-                .finish();
-            tracing::subscriber::set_global_default(subscriber).expect("setting default subscriber failed");
+            ($init_tracing)(&$args);
             if !$args.no_show_opts {
                 println!(
                     "current dir: {:?}\nenv_settings: {:#?}",
@@ -0,0 +1,33 @@
+//! AWS Lambda entrypoint: handles scheduled EventBridge events with `couchdb_backup::lambda::handle`
+//!
+//! Config is read from `CONFIG_PATH` (`config.yaml` by default, optional) and overridden by `COUCHDB_BACKUP__*` env
+//! vars and `config` of event; S3 credentials are those of the execution role if `token`/`secret` are not set.
+//! Log goes to CloudWatch as JSON, `LOG_FORMAT=pretty` for plain lines.
+use clap::ValueEnum;
+use lambda_runtime::{run, service_fn, Error, LambdaEvent};
+
+/// Time left to the invocation deadline for the chunk being uploaded when budget is spent, and for saving progress
+const DEADLINE_MARGIN: std::time::Duration = std::time::Duration::from_secs(120);
+
+async fn function_handler(
+    event: LambdaEvent<serde_json::Value>,
+) -> Result<couchdb_backup::report::BackupReport, Error> {
+    let config_path = std::env::var_os("CONFIG_PATH")
+        .map(std::path::PathBuf::from)
+        .unwrap_or_else(|| std::path::PathBuf::from("config.yaml"));
+    let budget = std::time::UNIX_EPOCH
+        .checked_add(std::time::Duration::from_millis(event.context.deadline))
+        .and_then(|deadline| deadline.duration_since(std::time::SystemTime::now()).ok())
+        .map(|left| left.saturating_sub(DEADLINE_MARGIN));
+    Ok(couchdb_backup::lambda::handle(event.payload, &config_path, budget).await?)
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Error> {
+    let log_format = match std::env::var("LOG_FORMAT") {
+        Ok(s) => couchdb_backup::LogFormat::from_str(&s, true)?,
+        Err(_) => couchdb_backup::LogFormat::Json,
+    };
+    couchdb_backup::init_tracing(log_format);
+    run(service_fn(function_handler)).await
+}
@@ -0,0 +1,241 @@
+#[allow(unused_imports)]
+use anyhow::{anyhow, bail, Context, Error, Result};
+
+use super::*;
+use s3_bucket::{ListArg, ListRet, S3Bucket};
+
+/// `bucket` setting: `s3://${bucket}/${prefix}` or just `${bucket}`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BucketUrl {
+    pub bucket: String,
+    /// Key prefix without leading and trailing `/`, may be empty
+    pub prefix: String,
+}
+
+impl std::str::FromStr for BucketUrl {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self> {
+        let path = s.strip_prefix("s3://").unwrap_or(s);
+        let (bucket, prefix) = path.split_once('/').unwrap_or((path, ""));
+        if bucket.is_empty() {
+            bail!("no bucket name in {s:?}, expected s3://bucket/folder");
+        }
+        Ok(Self {
+            bucket: bucket.to_owned(),
+            prefix: prefix.trim_matches('/').to_owned(),
+        })
+    }
+}
+
+pub fn bucket_url() -> Result<BucketUrl> {
+    let bucket = settings!(bucket).clone();
+    bucket
+        .parse()
+        .map_err(|err| anyhow!("failed to parse bucket {bucket:?}: {err}"))
+}
+
+/// Region of `s3` setting, `None` if neither `region` nor `endpoint` is set
+fn region(s3: &SettingsS3) -> Result<Option<s3_bucket::Region>> {
+    Ok(match (s3.region.clone(), s3.endpoint.clone()) {
+        (None, None) => None,
+        (Some(name), None) => Some(
+            name.parse()
+                .map_err(|err| anyhow!("unknown AWS region {name:?}: {err}"))?,
+        ),
+        (name, Some(endpoint)) => Some(s3_bucket::Region::Custom {
+            name: name.unwrap_or_else(|| "us-east-1".to_owned()),
+            endpoint,
+        }),
+    })
+}
+
+/// Bucket of `bucket` setting at `s3` region; uses `token`/`secret` if set, default credential chain of `s3_bucket` otherwise
+pub fn s3_bucket() -> Result<S3Bucket> {
+    let BucketUrl { bucket, .. } = bucket_url()?;
+    let s3 = settings!(s3).clone().unwrap_or_default();
+    let mut builder = s3_bucket::S3BucketBuilder::new(bucket.clone())
+        .addressing(s3.addressing)
+        .dual_stack(s3.dual_stack)
+        .server_side_encryption(s3.server_side_encryption)
+        .sse_kms_key_id(s3.sse_kms_key_id.clone());
+    if let Some(retries) = s3.retries {
+        builder = builder.retries(retries);
+    }
+    if let Some(region) = region(&s3)? {
+        builder = builder.region(region);
+    }
+    let builder = match (settings!(token).clone(), settings!(secret).clone()) {
+        (Some(token), Some(secret)) => {
+            builder.provider(s3_bucket::StaticProvider::new(token, secret, None, None))
+        }
+        (None, None) => builder,
+        _ => bail!("both `token` and `secret` must be set, or none of them"),
+    };
+    builder
+        .build()
+        .map_err(|err| anyhow!("S3BucketBuilder::new({bucket:?}): {err}"))
+}
+
+/// Multipart upload of `s3.part_size` MiB parts, `s3.concurrency` at once
+pub fn multipart_arg() -> s3_bucket::MultipartArg {
+    let s3 = settings!(s3).clone().unwrap_or_default();
+    let mut arg = s3_bucket::MultipartArg::new().content_type(Some("application/json".to_owned()));
+    if let Some(part_size) = s3.part_size {
+        arg = arg.part_size(part_size * 1024 * 1024);
+    }
+    if let Some(concurrency) = s3.concurrency {
+        arg = arg.concurrency(concurrency);
+    }
+    arg
+}
+
+/// `${bucket_prefix}/${prefix}/${year}/${month}/${day}/${suffix}/${db_name}`, where `bucket_prefix` is path of `s3://` url
+/// of `bucket` setting; fails if `bucket` cannot be parsed
+pub fn db_prefix(date: &impl chrono::Datelike, db_name: &str) -> Result<String> {
+    let prefix = settings!(prefix).clone();
+    let suffix = settings!(suffix).clone();
+    let day = date.day();
+    let month = date.month();
+    let year = date.year();
+    let db_prefix = format!("{prefix}/{year}/{month:02}/{day:02}/{suffix}/{db_name}");
+    let BucketUrl { prefix, .. } = bucket_url()?;
+    Ok(if prefix.is_empty() {
+        db_prefix
+    } else {
+        format!("{prefix}/{db_prefix}")
+    })
+}
+
+/// `${bucket_prefix}/${prefix}/checkpoint/${suffix}/${db_name}.json`, see `manifest::Checkpoint`
+pub fn checkpoint_key(db_name: &str) -> Result<String> {
+    let prefix = settings!(prefix).clone();
+    let suffix = settings!(suffix).clone();
+    let key = format!("{prefix}/checkpoint/{suffix}/{db_name}.json");
+    let BucketUrl { prefix, .. } = bucket_url()?;
+    Ok(if prefix.is_empty() {
+        key
+    } else {
+        format!("{prefix}/{key}")
+    })
+}
+
+pub fn chunk_key(db_prefix: &str, chunk_id: usize, codec: encode::Codec) -> String {
+    format!("{db_prefix}/{chunk_id:03}.{}", codec.extension())
+}
+
+/// `${db_prefix}/design.json[.gz|.zst|.xz]`, see `manifest::Manifest::design`
+pub fn design_key(db_prefix: &str, codec: encode::Codec) -> String {
+    format!("{db_prefix}/design.{}", codec.extension())
+}
+
+/// Codec of `key` if it is `${db_prefix}/NNN.json[.gz|.zst|.xz]`, not a key of some nested database
+pub fn chunk_codec(db_prefix: &str, key: &str) -> Option<encode::Codec> {
+    let codec = encode::Codec::of_key(key)?;
+    key.strip_prefix(db_prefix)
+        .and_then(|s| s.strip_prefix('/'))
+        .and_then(|s| s.strip_suffix(codec.extension()))
+        .and_then(|s| s.strip_suffix('.'))
+        .filter(|s| !s.is_empty() && s.chars().all(|ch| ch.is_ascii_digit()))
+        .map(|_| codec)
+}
+
+pub fn is_chunk_key(db_prefix: &str, key: &str) -> bool {
+    chunk_codec(db_prefix, key).is_some()
+}
+
+pub async fn list_keys(s3b: &S3Bucket, prefix: &str) -> Result<Vec<String>> {
+    let mut ret = vec![];
+    let mut list_arg = ListArg::new().prefix(prefix);
+    loop {
+        let (continuation_token, items) = match s3b
+            .list(list_arg)
+            .await
+            .map_err(|err| anyhow!("failed to list {prefix:?}: {err}"))?
+        {
+            ListRet::ToBeContinue(continuation_token, items) => (Some(continuation_token), items),
+            ListRet::Finished(items) => (None, items.unwrap_or_default()),
+        };
+        ret.extend(items.into_iter().filter_map(|item| item.key));
+        if let Some(continuation_token) = continuation_token {
+            list_arg = ListArg::new()
+                .prefix(prefix)
+                .continuation_token(continuation_token);
+        } else {
+            break;
+        }
+    }
+    Ok(ret)
+}
+
+pub async fn download(s3b: &S3Bucket, key: &str) -> Result<Vec<u8>> {
+    use tokio::io::AsyncReadExt;
+    let body = s3b
+        .download(key.to_owned())
+        .await
+        .map_err(|err| anyhow!("failed to download {key:?}: {err}"))?
+        .ok_or_else(|| anyhow!("no body for {key:?}"))?;
+    let mut ret = vec![];
+    body.into_async_read()
+        .read_to_end(&mut ret)
+        .await
+        .map_err(|err| anyhow!("failed to read {key:?}: {err}"))?;
+    Ok(ret)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bucket_url() {
+        assert_eq!(
+            "s3://data.example.com/folder/"
+                .parse::<BucketUrl>()
+                .unwrap(),
+            BucketUrl {
+                bucket: "data.example.com".to_owned(),
+                prefix: "folder".to_owned()
+            }
+        );
+        assert_eq!(
+            "backup".parse::<BucketUrl>().unwrap(),
+            BucketUrl {
+                bucket: "backup".to_owned(),
+                prefix: "".to_owned()
+            }
+        );
+        assert!("s3:///folder".parse::<BucketUrl>().is_err());
+    }
+
+    #[test]
+    fn test_is_chunk_key() {
+        let db_prefix = "backup/ippbx/2023/11/25/couchdb/account/ab/cd/0123";
+        assert!(is_chunk_key(
+            db_prefix,
+            "backup/ippbx/2023/11/25/couchdb/account/ab/cd/0123/000.json.gz"
+        ));
+        assert!(is_chunk_key(
+            db_prefix,
+            "backup/ippbx/2023/11/25/couchdb/account/ab/cd/0123/1234.json.gz"
+        ));
+        assert!(!is_chunk_key(
+            db_prefix,
+            "backup/ippbx/2023/11/25/couchdb/account/ab/cd/0123-202310/000.json.gz"
+        ));
+        assert!(!is_chunk_key(
+            db_prefix,
+            "backup/ippbx/2023/11/25/couchdb/account/ab/cd/0123/nested/000.json.gz"
+        ));
+        assert!(!is_chunk_key(
+            db_prefix,
+            "backup/ippbx/2023/11/25/couchdb/account/ab/cd/0123/manifest.json"
+        ));
+        assert_eq!(
+            chunk_codec(
+                db_prefix,
+                "backup/ippbx/2023/11/25/couchdb/account/ab/cd/0123/001.json.zst"
+            ),
+            Some(encode::Codec::Zstd)
+        );
+    }
+}
@@ -0,0 +1,277 @@
+#[allow(unused_imports)]
+use anyhow::{anyhow, bail, Context, Error, Result};
+
+use chrono::{Datelike, NaiveDate, NaiveDateTime, NaiveTime, Timelike, Weekday};
+
+/// Calendar event expression in systemd.time(7) syntax: `[WEEKDAYS] [[YEAR-]MONTH-DAY] [HOUR:MINUTE[:SECOND]]`,
+/// e.g. `Sat *-*-1..7 18:00:00`, or one of shorthands `minutely`, `hourly`, `daily`, `weekly`, `monthly`,
+/// `quarterly`, `semiannually`, `yearly` (`annually`).
+///
+/// Each component is `*`, a value, a range `a..b`, a repetition `a/step` (`a..b/step`) or a comma separated list of them.
+/// Time zones are not supported: expression is evaluated in UTC, the same as dates in backup keys
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Calendar {
+    weekdays: Option<Vec<Weekday>>,
+    years: Option<Vec<u32>>,
+    months: Vec<u32>,
+    days: Vec<u32>,
+    hours: Vec<u32>,
+    minutes: Vec<u32>,
+    seconds: Vec<u32>,
+}
+
+/// How far `next_after` looks ahead before it gives up
+const MAX_LOOKAHEAD_DAYS: i64 = 366 * 8;
+
+impl std::str::FromStr for Calendar {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let expanded = match s.to_lowercase().as_str() {
+            "minutely" => Some("*-*-* *:*:00"),
+            "hourly" => Some("*-*-* *:00:00"),
+            "daily" => Some("*-*-* 00:00:00"),
+            "weekly" => Some("Mon *-*-* 00:00:00"),
+            "monthly" => Some("*-*-01 00:00:00"),
+            "quarterly" => Some("*-01,04,07,10-01 00:00:00"),
+            "semiannually" => Some("*-01,07-01 00:00:00"),
+            "yearly" | "annually" => Some("*-01-01 00:00:00"),
+            _ => None,
+        };
+        let s = expanded.unwrap_or(s);
+
+        let mut weekdays = None;
+        let mut date = None;
+        let mut time = None;
+        for token in s.split_whitespace() {
+            if token.contains(':') {
+                if time.replace(token).is_some() {
+                    bail!("{s:?}: time is specified twice");
+                }
+            } else if token.starts_with(|ch: char| ch.is_ascii_alphabetic()) {
+                if weekdays.replace(parse_weekdays(token)?).is_some() {
+                    bail!("{s:?}: weekdays are specified twice");
+                }
+            } else if date.replace(token).is_some() {
+                bail!("{s:?}: date is specified twice");
+            }
+        }
+        if weekdays.is_none() && date.is_none() && time.is_none() {
+            bail!("empty calendar expression");
+        }
+
+        let (years, months, days) = match date {
+            None => (None, parse_field("*", 1, 12)?, parse_field("*", 1, 31)?),
+            Some(date) => {
+                let parts = date.split('-').collect::<Vec<_>>();
+                let (year, month, day) = match parts[..] {
+                    [year, month, day] => (Some(year), month, day),
+                    [month, day] => (None, month, day),
+                    _ => bail!("{s:?}: invalid date {date:?}"),
+                };
+                (
+                    match year {
+                        None | Some("*") => None,
+                        Some(year) => Some(parse_field(year, 1970, 2199)?),
+                    },
+                    parse_field(month, 1, 12)?,
+                    parse_field(day, 1, 31)?,
+                )
+            }
+        };
+        let (hours, minutes, seconds) = match time {
+            None => (vec![0], vec![0], vec![0]),
+            Some(time) => match time.split(':').collect::<Vec<_>>()[..] {
+                [hour, minute] => (
+                    parse_field(hour, 0, 23)?,
+                    parse_field(minute, 0, 59)?,
+                    vec![0],
+                ),
+                [hour, minute, second] => (
+                    parse_field(hour, 0, 23)?,
+                    parse_field(minute, 0, 59)?,
+                    parse_field(second, 0, 59)?,
+                ),
+                _ => bail!("{s:?}: invalid time {time:?}"),
+            },
+        };
+        Ok(Self {
+            weekdays,
+            years,
+            months,
+            days,
+            hours,
+            minutes,
+            seconds,
+        })
+    }
+}
+
+impl Calendar {
+    /// Next elapse strictly after `after`, `None` if there is no elapse in the foreseeable future
+    pub fn next_after(&self, after: NaiveDateTime) -> Option<NaiveDateTime> {
+        let start = after.with_nanosecond(0)? + chrono::Duration::seconds(1);
+        let mut date = start.date();
+        let last_date = date + chrono::Duration::days(MAX_LOOKAHEAD_DAYS);
+        while date <= last_date {
+            if self.matches_date(date) {
+                let not_before = if date == start.date() {
+                    start.time()
+                } else {
+                    NaiveTime::MIN
+                };
+                if let Some(time) = self.first_time_not_before(not_before) {
+                    return Some(date.and_time(time));
+                }
+            }
+            date = date.succ_opt()?;
+        }
+        None
+    }
+
+    fn matches_date(&self, date: NaiveDate) -> bool {
+        self.weekdays
+            .as_ref()
+            .map(|weekdays| weekdays.contains(&date.weekday()))
+            .unwrap_or(true)
+            && self
+                .years
+                .as_ref()
+                .map(|years| years.contains(&(date.year() as u32)))
+                .unwrap_or(true)
+            && self.months.contains(&date.month())
+            && self.days.contains(&date.day())
+    }
+
+    fn first_time_not_before(&self, not_before: NaiveTime) -> Option<NaiveTime> {
+        for &hour in self.hours.iter().filter(|&&h| h >= not_before.hour()) {
+            for &minute in self.minutes.iter() {
+                for &second in self.seconds.iter() {
+                    let time = NaiveTime::from_hms_opt(hour, minute, second)?;
+                    if time >= not_before {
+                        return Some(time);
+                    }
+                }
+            }
+        }
+        None
+    }
+}
+
+fn parse_weekdays(s: &str) -> Result<Vec<Weekday>> {
+    let mut ret = vec![];
+    for part in s.split(',').filter(|part| !part.is_empty()) {
+        match part.split_once("..") {
+            None => ret.push(parse_weekday(part)?),
+            Some((from, to)) => {
+                let (from, to) = (parse_weekday(from)?, parse_weekday(to)?);
+                let mut weekday = from;
+                ret.push(weekday);
+                while weekday != to {
+                    weekday = weekday.succ();
+                    ret.push(weekday);
+                }
+            }
+        }
+    }
+    Ok(ret)
+}
+
+fn parse_weekday(s: &str) -> Result<Weekday> {
+    s.parse::<Weekday>()
+        .map_err(|_| anyhow!("invalid weekday {s:?}"))
+}
+
+fn parse_field(s: &str, min: u32, max: u32) -> Result<Vec<u32>> {
+    let parse_value = |s: &str| -> Result<u32> {
+        let value = s
+            .parse::<u32>()
+            .map_err(|err| anyhow!("invalid value {s:?}: {err}"))?;
+        if !(min..=max).contains(&value) {
+            bail!("value {value} is out of range {min}..{max}");
+        }
+        Ok(value)
+    };
+    let mut ret = vec![];
+    for part in s.split(',') {
+        let (range, step) = match part.split_once('/') {
+            None => (part, None),
+            Some((range, step)) => (
+                range,
+                Some(
+                    step.parse::<u32>()
+                        .ok()
+                        .filter(|&step| step > 0)
+                        .ok_or_else(|| anyhow!("invalid repetition {step:?} in {s:?}"))?,
+                ),
+            ),
+        };
+        let (from, to) = if range == "*" {
+            (min, max)
+        } else if let Some((from, to)) = range.split_once("..") {
+            (parse_value(from)?, parse_value(to)?)
+        } else {
+            let value = parse_value(range)?;
+            (value, if step.is_some() { max } else { value })
+        };
+        if from > to {
+            bail!("invalid range {range:?} in {s:?}");
+        }
+        ret.extend((from..=to).step_by(step.unwrap_or(1) as usize));
+    }
+    ret.sort();
+    ret.dedup();
+    Ok(ret)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(s: &str) -> NaiveDateTime {
+        NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M:%S").unwrap()
+    }
+
+    #[test]
+    fn test_first_saturday() {
+        let calendar = "Sat *-*-1..7 18:00:00".parse::<Calendar>().unwrap();
+        // 2023-11-04 is the first Saturday of November 2023
+        assert_eq!(
+            calendar.next_after(at("2023-11-01 00:00:00")),
+            Some(at("2023-11-04 18:00:00"))
+        );
+        assert_eq!(
+            calendar.next_after(at("2023-11-04 18:00:00")),
+            Some(at("2023-12-02 18:00:00"))
+        );
+    }
+
+    #[test]
+    fn test_shorthands_and_lists() {
+        let calendar = "daily".parse::<Calendar>().unwrap();
+        assert_eq!(
+            calendar.next_after(at("2023-11-04 18:00:00")),
+            Some(at("2023-11-05 00:00:00"))
+        );
+        let calendar = "Mon..Fri 09,18:30".parse::<Calendar>().unwrap();
+        assert_eq!(
+            calendar.next_after(at("2023-11-03 18:30:00")),
+            Some(at("2023-11-06 09:30:00"))
+        );
+        let calendar = "*-*-* *:0/15".parse::<Calendar>().unwrap();
+        assert_eq!(
+            calendar.next_after(at("2023-11-03 18:31:12")),
+            Some(at("2023-11-03 18:45:00"))
+        );
+        let calendar = "2023-02-30".parse::<Calendar>().unwrap();
+        assert_eq!(calendar.next_after(at("2023-01-01 00:00:00")), None);
+    }
+
+    #[test]
+    fn test_invalid() {
+        assert!("".parse::<Calendar>().is_err());
+        assert!("Sat *-*-1..7 25:00:00".parse::<Calendar>().is_err());
+        assert!("Sut".parse::<Calendar>().is_err());
+        assert!("*-*-7..1".parse::<Calendar>().is_err());
+    }
+}
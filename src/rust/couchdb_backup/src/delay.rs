@@ -0,0 +1,237 @@
+#[allow(unused_imports)]
+use anyhow::{anyhow, bail, Context, Error, Result};
+
+use super::*;
+use std::sync::OnceLock;
+use std::time::Duration;
+use tokio_util::sync::CancellationToken;
+
+/// Returned by `sleep` (and everything that waits on it) when Ctrl-C is pressed
+#[derive(Debug)]
+pub struct Interrupted;
+
+impl std::fmt::Display for Interrupted {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "interrupted by Ctrl-C")
+    }
+}
+
+impl std::error::Error for Interrupted {}
+
+static CTRL_C: OnceLock<CancellationToken> = OnceLock::new();
+
+/// Token cancelled by Ctrl-C. The first call (at the start of `run()`) spawns the only listener of Ctrl-C, which
+/// replaces default handler: the first Ctrl-C cancels the token, so backup stops at the next chunk or database,
+/// the second one exits
+pub fn ctrl_c() -> CancellationToken {
+    CTRL_C
+        .get_or_init(|| {
+            let token = CancellationToken::new();
+            let cancel = token.clone();
+            tokio::spawn(async move {
+                if tokio::signal::ctrl_c().await.is_err() {
+                    return;
+                }
+                warn!("{Interrupted}, will stop at the next chunk; Ctrl-C again to exit now");
+                cancel.cancel();
+                if tokio::signal::ctrl_c().await.is_ok() {
+                    std::process::exit(report::exit_code::INTERRUPTED);
+                }
+            });
+            token
+        })
+        .clone()
+}
+
+/// Whether Ctrl-C is pressed since `ctrl_c()` is called
+pub fn is_interrupted() -> bool {
+    CTRL_C.get().is_some_and(|token| token.is_cancelled())
+}
+
+/// Sleeps for `duration` unless `interrupt` is cancelled
+pub async fn sleep(duration: Duration, interrupt: &CancellationToken) -> Result<()> {
+    tokio::select! {
+        _ = tokio::time::sleep(duration) => Ok(()),
+        _ = interrupt.cancelled() => Err(Interrupted.into()),
+    }
+}
+
+/// Pause between databases: `delay`, lengthened while CouchDB is under load if `adaptive_delay` is set; fails with
+/// `Interrupted` once `interrupt` is cancelled
+pub async fn pause(
+    client: &couch_rs::Client,
+    delay: Duration,
+    adaptive_delay: Option<&SettingsAdaptiveDelay>,
+    interrupt: &CancellationToken,
+) -> Result<()> {
+    if interrupt.is_cancelled() {
+        return Err(Interrupted.into());
+    }
+    if !delay.is_zero() {
+        info!("will pause for {}", arrange_millis::get(delay.as_millis()));
+        sleep(delay, interrupt).await?;
+    }
+    let Some(adaptive_delay) = adaptive_delay else {
+        return Ok(());
+    };
+    let max_delay = Duration::from_secs(adaptive_delay.max_delay);
+    let mut paused = delay;
+    let mut extra = delay.max(Duration::from_secs(1));
+    while paused < max_delay {
+        let Some(reason) = under_load(client, adaptive_delay).await else {
+            break;
+        };
+        let extra_now = extra.min(max_delay - paused);
+        info!(
+            "CouchDB is under load ({reason}), will pause for {} more",
+            arrange_millis::get(extra_now.as_millis())
+        );
+        sleep(extra_now, interrupt).await?;
+        paused += extra_now;
+        extra *= 2;
+    }
+    Ok(())
+}
+
+/// Reason why CouchDB is considered under load, if it is
+async fn under_load(
+    client: &couch_rs::Client,
+    adaptive_delay: &SettingsAdaptiveDelay,
+) -> Option<String> {
+    let start = std::time::Instant::now();
+    let active_tasks = match client
+        .req(reqwest::Method::GET, "/_active_tasks", None)
+        .send()
+        .await
+        .and_then(|resp| resp.error_for_status())
+    {
+        Ok(resp) => resp.json::<Vec<serde_json::Value>>().await,
+        Err(err) => Err(err),
+    };
+    let latency = std::time::Instant::now().duration_since(start);
+    match active_tasks {
+        Err(err) => {
+            warn!("failed to get _active_tasks, will not adapt delay: {err}");
+            None
+        }
+        Ok(active_tasks) => {
+            if adaptive_delay
+                .active_tasks
+                .map(|max| active_tasks.len() > max)
+                .unwrap_or(false)
+            {
+                Some(format!("{} active tasks", active_tasks.len()))
+            } else if adaptive_delay
+                .latency
+                .map(|max| latency > Duration::from_millis(max))
+                .unwrap_or(false)
+            {
+                Some(format!(
+                    "_active_tasks responded in {}",
+                    arrange_millis::get(latency.as_millis())
+                ))
+            } else {
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    /// CouchDB stub answering `_active_tasks` with `active_tasks[i]` tasks (then none) to `i`-th request; returns
+    /// client and count of requests
+    async fn stub(active_tasks: Vec<usize>) -> (couch_rs::Client, Arc<AtomicUsize>) {
+        let requests = Arc::new(AtomicUsize::new(0));
+        let counter = requests.clone();
+        let mut active_tasks = active_tasks.into_iter();
+        let url = crate::stub::serve(move |_| {
+            counter.fetch_add(1, Ordering::Relaxed);
+            let count = active_tasks.next().unwrap_or_default();
+            (
+                200,
+                serde_json::to_string(&vec![serde_json::json!({}); count]).unwrap(),
+            )
+        })
+        .await;
+        (
+            couch_rs::Client::new(&url, "admin", "secret").unwrap(),
+            requests,
+        )
+    }
+
+    #[tokio::test]
+    async fn test_pause_adaptive() {
+        let adaptive_delay = SettingsAdaptiveDelay {
+            active_tasks: Some(2),
+            latency: None,
+            max_delay: 10,
+        };
+        // under load twice: pauses 1s, then 2s more, then is not under load
+        let (client, requests) = stub(vec![3, 3, 0]).await;
+        let start = std::time::Instant::now();
+        pause(
+            &client,
+            Duration::ZERO,
+            Some(&adaptive_delay),
+            &CancellationToken::new(),
+        )
+        .await
+        .unwrap();
+        assert!(start.elapsed() >= Duration::from_secs(3));
+        assert_eq!(requests.load(Ordering::Relaxed), 3);
+
+        // always under load: pause is bounded by `max_delay`
+        let adaptive_delay = SettingsAdaptiveDelay {
+            max_delay: 1,
+            ..adaptive_delay
+        };
+        let (client, _) = stub(vec![3, 3]).await;
+        let start = std::time::Instant::now();
+        pause(
+            &client,
+            Duration::ZERO,
+            Some(&adaptive_delay),
+            &CancellationToken::new(),
+        )
+        .await
+        .unwrap();
+        assert!(start.elapsed() < Duration::from_secs(2));
+    }
+
+    #[tokio::test]
+    async fn test_pause_interrupted() {
+        let (client, _) = stub(vec![3, 3, 3]).await;
+        let adaptive_delay = SettingsAdaptiveDelay {
+            active_tasks: Some(2),
+            latency: None,
+            max_delay: 60,
+        };
+        let interrupt = CancellationToken::new();
+        let cancel = interrupt.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            cancel.cancel();
+        });
+        let start = std::time::Instant::now();
+        let err = pause(
+            &client,
+            Duration::from_secs(60),
+            Some(&adaptive_delay),
+            &interrupt,
+        )
+        .await
+        .unwrap_err();
+        assert!(err.is::<Interrupted>());
+        assert!(start.elapsed() < Duration::from_secs(5));
+        // `_active_tasks` is not polled once interrupted
+        let err = pause(&client, Duration::ZERO, Some(&adaptive_delay), &interrupt)
+            .await
+            .unwrap_err();
+        assert!(err.is::<Interrupted>());
+    }
+}
@@ -0,0 +1,295 @@
+#[allow(unused_imports)]
+use anyhow::{anyhow, bail, Context, Error, Result};
+
+use crate::encrypt::{EncryptWriter, Key};
+use async_compression::tokio::write::{GzipEncoder, XzEncoder, ZstdEncoder};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::Digest;
+use std::pin::Pin;
+use std::task::{Context as TaskContext, Poll};
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+
+/// Compression of chunks
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Codec {
+    #[default]
+    Gzip,
+    Zstd,
+    Xz,
+    None,
+}
+
+impl Codec {
+    pub const ALL: [Codec; 4] = [Codec::Gzip, Codec::Zstd, Codec::Xz, Codec::None];
+
+    /// Extension of chunk key
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Codec::Gzip => "json.gz",
+            Codec::Zstd => "json.zst",
+            Codec::Xz => "json.xz",
+            Codec::None => "json",
+        }
+    }
+    /// Codec of chunk `key` by its extension
+    pub fn of_key(key: &str) -> Option<Self> {
+        // "json" is a suffix of no other extension, so the first match is the right one
+        Self::ALL.into_iter().find(|codec| {
+            key.strip_suffix(codec.extension())
+                .map(|s| s.ends_with('.'))
+                .unwrap_or(false)
+        })
+    }
+    /// Decompresses `compressed` and parses it as array of documents
+    pub fn decode(&self, compressed: &[u8]) -> Result<Vec<Value>> {
+        match self {
+            Codec::Gzip => serde_json::from_reader(flate2::read::GzDecoder::new(compressed)),
+            Codec::Zstd => serde_json::from_reader(
+                zstd::stream::read::Decoder::new(compressed)
+                    .map_err(|err| anyhow!("failed to decode chunk: {err}"))?,
+            ),
+            Codec::Xz => serde_json::from_reader(xz2::read::XzDecoder::new(compressed)),
+            Codec::None => serde_json::from_slice(compressed),
+        }
+        .map_err(|err| anyhow!("failed to decode {self:?} chunk: {err}"))
+    }
+}
+
+/// `compression` of task
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Compression {
+    #[serde(default)]
+    pub codec: Codec,
+    /// Default of codec if not set: gzip 0..=9, zstd 1..=22, xz 0..=9
+    pub level: Option<i32>,
+}
+
+/// Passes bytes to `inner`, counting them and hashing them with SHA-256
+pub struct HashWriter<W> {
+    inner: W,
+    len: usize,
+    sha256: sha2::Sha256,
+}
+
+impl<W> HashWriter<W> {
+    pub fn new(inner: W) -> Self {
+        Self {
+            inner,
+            len: 0,
+            sha256: sha2::Sha256::new(),
+        }
+    }
+    pub fn len(&self) -> usize {
+        self.len
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+    /// Hex encoded SHA-256 of bytes written so far
+    pub fn sha256(&self) -> String {
+        format!("{:x}", self.sha256.clone().finalize())
+    }
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for HashWriter<W> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+        let ret = Pin::new(&mut this.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = ret {
+            this.len += n;
+            this.sha256.update(&buf[..n]);
+        }
+        ret
+    }
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+/// Encoder of `Codec`
+enum Compressor<W: AsyncWrite + Unpin> {
+    Gzip(GzipEncoder<W>),
+    Zstd(ZstdEncoder<W>),
+    Xz(XzEncoder<W>),
+    None(W),
+}
+
+impl<W: AsyncWrite + Unpin> Compressor<W> {
+    fn new(inner: W, compression: Compression) -> Self {
+        use async_compression::Level;
+        let level = compression
+            .level
+            .map(Level::Precise)
+            .unwrap_or(Level::Default);
+        match compression.codec {
+            Codec::Gzip => Self::Gzip(GzipEncoder::with_quality(inner, level)),
+            Codec::Zstd => Self::Zstd(ZstdEncoder::with_quality(inner, level)),
+            Codec::Xz => Self::Xz(XzEncoder::with_quality(inner, level)),
+            Codec::None => Self::None(inner),
+        }
+    }
+    fn into_inner(self) -> W {
+        match self {
+            Self::Gzip(encoder) => encoder.into_inner(),
+            Self::Zstd(encoder) => encoder.into_inner(),
+            Self::Xz(encoder) => encoder.into_inner(),
+            Self::None(inner) => inner,
+        }
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for Compressor<W> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        match self.get_mut() {
+            Self::Gzip(encoder) => Pin::new(encoder).poll_write(cx, buf),
+            Self::Zstd(encoder) => Pin::new(encoder).poll_write(cx, buf),
+            Self::Xz(encoder) => Pin::new(encoder).poll_write(cx, buf),
+            Self::None(inner) => Pin::new(inner).poll_write(cx, buf),
+        }
+    }
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            Self::Gzip(encoder) => Pin::new(encoder).poll_flush(cx),
+            Self::Zstd(encoder) => Pin::new(encoder).poll_flush(cx),
+            Self::Xz(encoder) => Pin::new(encoder).poll_flush(cx),
+            Self::None(inner) => Pin::new(inner).poll_flush(cx),
+        }
+    }
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            Self::Gzip(encoder) => Pin::new(encoder).poll_shutdown(cx),
+            Self::Zstd(encoder) => Pin::new(encoder).poll_shutdown(cx),
+            Self::Xz(encoder) => Pin::new(encoder).poll_shutdown(cx),
+            Self::None(inner) => Pin::new(inner).poll_shutdown(cx),
+        }
+    }
+}
+
+/// Streams docs as JSON array through compressor (and encryptor if there is a key) into `W`, one doc at a time, so
+/// memory does not depend on size of chunk
+pub struct ChunkEncoder<W: AsyncWrite + Unpin> {
+    compressor: Compressor<EncryptWriter<HashWriter<W>>>,
+    docs: usize,
+    bytes_raw: usize,
+}
+
+/// What `ChunkEncoder::finish` returns
+#[derive(Debug)]
+pub struct Encoded {
+    pub docs: usize,
+    /// Size of JSON
+    pub bytes_raw: usize,
+    /// Size of compressed (and encrypted) JSON
+    pub bytes_compressed: usize,
+    /// Hex encoded SHA-256 of compressed (and encrypted) JSON
+    pub sha256: String,
+}
+
+impl<W: AsyncWrite + Unpin> ChunkEncoder<W> {
+    pub fn new(inner: W, compression: Compression, key: Option<&Key>) -> Self {
+        Self {
+            compressor: Compressor::new(
+                EncryptWriter::new(HashWriter::new(inner), key),
+                compression,
+            ),
+            docs: 0,
+            bytes_raw: 0,
+        }
+    }
+    async fn write(&mut self, bytes: &[u8]) -> Result<()> {
+        self.compressor.write_all(bytes).await?;
+        self.bytes_raw += bytes.len();
+        Ok(())
+    }
+    /// Serialization error is `serde_json::Error`, the rest are I/O errors of `W`
+    pub async fn write_doc(&mut self, doc: &Value) -> Result<()> {
+        let json = serde_json::to_vec(doc)?;
+        self.write(if self.docs == 0 { b"[" } else { b"," }).await?;
+        self.write(&json).await?;
+        self.docs += 1;
+        Ok(())
+    }
+    /// Closes array, finishes compression and shuts `W` down
+    pub async fn finish(mut self) -> Result<(W, Encoded)> {
+        self.write(if self.docs == 0 { b"[]" } else { b"]" })
+            .await?;
+        self.compressor.shutdown().await?;
+        let hash_writer = self.compressor.into_inner().into_inner();
+        let encoded = Encoded {
+            docs: self.docs,
+            bytes_raw: self.bytes_raw,
+            bytes_compressed: hash_writer.len(),
+            sha256: hash_writer.sha256(),
+        };
+        Ok((hash_writer.into_inner(), encoded))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_chunk_encoder() {
+        let docs = vec![
+            serde_json::json!({ "_id": "a", "n": 1 }),
+            serde_json::json!({ "_id": "b", "s": "текст" }),
+        ];
+        let key = Key::new(
+            "test".to_owned(),
+            "MDEyMzQ1Njc4OWFiY2RlZjAxMjM0NTY3ODlhYmNkZWY=",
+        )
+        .unwrap();
+        for (codec, key) in Codec::ALL
+            .into_iter()
+            .flat_map(|codec| [(codec, None), (codec, Some(&key))])
+        {
+            let compression = Compression { codec, level: None };
+            let mut encoder = ChunkEncoder::new(Vec::new(), compression, key);
+            for doc in docs.iter() {
+                encoder.write_doc(doc).await.unwrap();
+            }
+            let (compressed, encoded) = encoder.finish().await.unwrap();
+            assert_eq!(encoded.docs, 2);
+            assert_eq!(encoded.bytes_raw, serde_json::to_vec(&docs).unwrap().len());
+            assert_eq!(encoded.bytes_compressed, compressed.len());
+            assert_eq!(encoded.sha256, crate::manifest::sha256(&compressed));
+            assert_eq!(crate::encrypt::is_encrypted(&compressed), key.is_some());
+            let compressed = crate::encrypt::decrypt(compressed, key).unwrap();
+            assert_eq!(codec.decode(&compressed).unwrap(), docs);
+
+            let (compressed, _) = ChunkEncoder::new(Vec::new(), compression, key)
+                .finish()
+                .await
+                .unwrap();
+            let compressed = crate::encrypt::decrypt(compressed, key).unwrap();
+            assert!(codec.decode(&compressed).unwrap().is_empty());
+        }
+    }
+
+    #[test]
+    fn test_codec_of_key() {
+        assert_eq!(Codec::of_key("db/000.json.gz"), Some(Codec::Gzip));
+        assert_eq!(Codec::of_key("db/000.json.zst"), Some(Codec::Zstd));
+        assert_eq!(Codec::of_key("db/000.json.xz"), Some(Codec::Xz));
+        assert_eq!(Codec::of_key("db/000.json"), Some(Codec::None));
+        assert_eq!(Codec::of_key("db/manifest.yaml"), None);
+        assert_eq!(Codec::of_key("db/000json"), None);
+    }
+}
@@ -0,0 +1,314 @@
+#[allow(unused_imports)]
+use anyhow::{anyhow, bail, Context, Error, Result};
+
+use super::*;
+use aes_gcm::aead::rand_core::RngCore;
+use aes_gcm::aead::stream::{DecryptorBE32, EncryptorBE32};
+use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng};
+use aes_gcm::Aes256Gcm;
+use std::pin::Pin;
+use std::task::{ready, Context as TaskContext, Poll};
+use tokio::io::AsyncWrite;
+
+/// Starts each encrypted object
+const MAGIC: &[u8] = b"CBENC\x00\x00\x01";
+/// Plaintext of each segment but the last one
+const SEGMENT_SIZE: usize = 64 * 1024;
+const TAG_SIZE: usize = 16;
+const NONCE_SIZE: usize = 12;
+/// Nonce of segment is prefix, 32-bit counter and last segment flag
+const NONCE_PREFIX_SIZE: usize = 7;
+const WRAPPED_KEY_SIZE: usize = NONCE_SIZE + 32 + TAG_SIZE;
+
+/// Master key of `encryption` setting: wraps random data key of each object
+#[derive(Clone)]
+pub struct Key {
+    id: String,
+    cipher: Aes256Gcm,
+}
+
+impl std::fmt::Debug for Key {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Key").field("id", &self.id).finish()
+    }
+}
+
+impl Key {
+    /// `key` is base64 of 32 bytes, e.g. output of `openssl rand -base64 32`
+    pub fn new(id: String, key: &str) -> Result<Self> {
+        use base64::Engine;
+        if id.is_empty() || id.len() > u8::MAX as usize {
+            bail!("key id must be 1..=255 bytes long, got {id:?}");
+        }
+        let key = base64::engine::general_purpose::STANDARD
+            .decode(key.trim())
+            .map_err(|err| anyhow!("key {id:?} is not base64: {err}"))?;
+        let cipher = Aes256Gcm::new_from_slice(&key)
+            .map_err(|_| anyhow!("key {id:?} must be 32 bytes, got {}", key.len()))?;
+        Ok(Self { id, cipher })
+    }
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+}
+
+/// Key of `encryption` setting, `None` if it is not set
+pub fn key() -> Result<Option<Key>> {
+    let Some(encryption) = settings!(encryption).clone() else {
+        return Ok(None);
+    };
+    let key = match (encryption.key_file, encryption.key_env) {
+        (Some(key_file), None) => std::fs::read_to_string(&key_file)
+            .map_err(|err| anyhow!("failed to read key file {key_file:?}: {err}"))?,
+        (None, Some(key_env)) => std::env::var(&key_env)
+            .map_err(|err| anyhow!("failed to read key from env var {key_env:?}: {err}"))?,
+        _ => bail!("exactly one of `encryption.key_file` and `encryption.key_env` must be set"),
+    };
+    Key::new(encryption.key_id, &key).map(Some)
+}
+
+/// Header of encrypted object: magic, key id, data key wrapped by master key, nonce prefix of segments
+fn header(key: &Key) -> (Vec<u8>, EncryptorBE32<Aes256Gcm>) {
+    let data_key = Aes256Gcm::generate_key(&mut OsRng);
+    let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+    let wrapped = key
+        .cipher
+        .encrypt(
+            &nonce,
+            aes_gcm::aead::Payload {
+                msg: &data_key,
+                aad: key.id.as_bytes(),
+            },
+        )
+        .expect("encryption of 32 bytes does not fail");
+    let mut nonce_prefix = [0u8; NONCE_PREFIX_SIZE];
+    OsRng.fill_bytes(&mut nonce_prefix);
+
+    let mut ret = MAGIC.to_vec();
+    ret.push(key.id.len() as u8);
+    ret.extend_from_slice(key.id.as_bytes());
+    ret.extend_from_slice(&nonce);
+    ret.extend_from_slice(&wrapped);
+    ret.extend_from_slice(&nonce_prefix);
+    let encryptor = EncryptorBE32::from_aead(Aes256Gcm::new(&data_key), &nonce_prefix.into());
+    (ret, encryptor)
+}
+
+pub fn is_encrypted(data: &[u8]) -> bool {
+    data.starts_with(MAGIC)
+}
+
+/// Id of key `data` is encrypted with, `None` if it is not encrypted
+pub fn key_id(data: &[u8]) -> Option<String> {
+    let rest = data.strip_prefix(MAGIC)?;
+    let len = *rest.first()? as usize;
+    rest.get(1..1 + len)
+        .map(|id| String::from_utf8_lossy(id).into_owned())
+}
+
+/// Decrypts `data` if it is encrypted, returns it as is otherwise
+pub fn decrypt(data: Vec<u8>, key: Option<&Key>) -> Result<Vec<u8>> {
+    let Some(key_id) = key_id(&data) else {
+        if is_encrypted(&data) {
+            bail!("malformed header of encrypted object");
+        }
+        return Ok(data);
+    };
+    let key = match key {
+        Some(key) if key.id == key_id => key,
+        Some(key) => bail!(
+            "object is encrypted with key {key_id:?}, but key {:?} is configured",
+            key.id
+        ),
+        None => {
+            bail!("object is encrypted with key {key_id:?}, but `encryption` is not configured")
+        }
+    };
+    let rest = &data[MAGIC.len() + 1 + data[MAGIC.len()] as usize..];
+    if rest.len() < WRAPPED_KEY_SIZE + NONCE_PREFIX_SIZE {
+        bail!("malformed header of encrypted object");
+    }
+    let (wrapped, rest) = rest.split_at(WRAPPED_KEY_SIZE);
+    let (nonce_prefix, mut segments) = rest.split_at(NONCE_PREFIX_SIZE);
+    let (nonce, wrapped) = wrapped.split_at(NONCE_SIZE);
+    let data_key = key
+        .cipher
+        .decrypt(
+            nonce.into(),
+            aes_gcm::aead::Payload {
+                msg: wrapped,
+                aad: key.id.as_bytes(),
+            },
+        )
+        .map_err(|_| anyhow!("failed to unwrap data key with key {key_id:?}"))?;
+    let data_cipher =
+        Aes256Gcm::new_from_slice(&data_key).map_err(|_| anyhow!("malformed data key"))?;
+    let mut decryptor = DecryptorBE32::from_aead(data_cipher, nonce_prefix.into());
+    let mut ret = Vec::with_capacity(segments.len());
+    while segments.len() > SEGMENT_SIZE + TAG_SIZE {
+        let (segment, rest) = segments.split_at(SEGMENT_SIZE + TAG_SIZE);
+        ret.extend(
+            decryptor
+                .decrypt_next(segment)
+                .map_err(|_| anyhow!("failed to decrypt segment, object is corrupted"))?,
+        );
+        segments = rest;
+    }
+    ret.extend(decryptor.decrypt_last(segments).map_err(|_| {
+        anyhow!("failed to decrypt last segment, object is corrupted or truncated")
+    })?);
+    Ok(ret)
+}
+
+/// Encrypts `data` at once, see `EncryptWriter`
+pub fn encrypt(data: &[u8], key: &Key) -> Vec<u8> {
+    let (mut ret, mut encryptor) = header(key);
+    let mut segments = data.chunks(SEGMENT_SIZE).peekable();
+    while let Some(segment) = segments.next() {
+        if segments.peek().is_some() {
+            ret.extend(
+                encryptor
+                    .encrypt_next(segment)
+                    .expect("segment is not too long"),
+            );
+        } else {
+            ret.extend(
+                encryptor
+                    .encrypt_last(segment)
+                    .expect("segment is not too long"),
+            );
+            return ret;
+        }
+    }
+    ret.extend(
+        encryptor
+            .encrypt_last(&[][..])
+            .expect("segment is not too long"),
+    );
+    ret
+}
+
+/// Encrypts bytes written (AES-256-GCM of random data key, in segments of `SEGMENT_SIZE`) into `W`;
+/// passes them through as is if there is no key
+pub struct EncryptWriter<W> {
+    inner: W,
+    /// `None` if there is no key or after the last segment
+    encryptor: Option<EncryptorBE32<Aes256Gcm>>,
+    is_encrypted: bool,
+    buf: Vec<u8>,
+    /// Ciphertext not yet written to `inner`
+    out: Vec<u8>,
+    out_pos: usize,
+}
+
+impl<W: AsyncWrite + Unpin> EncryptWriter<W> {
+    pub fn new(inner: W, key: Option<&Key>) -> Self {
+        let (out, encryptor) = match key.map(header) {
+            Some((header, encryptor)) => (header, Some(encryptor)),
+            None => (vec![], None),
+        };
+        Self {
+            inner,
+            is_encrypted: encryptor.is_some(),
+            encryptor,
+            buf: vec![],
+            out,
+            out_pos: 0,
+        }
+    }
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+    fn poll_write_out(&mut self, cx: &mut TaskContext<'_>) -> Poll<std::io::Result<()>> {
+        while self.out_pos < self.out.len() {
+            let n = ready!(Pin::new(&mut self.inner).poll_write(cx, &self.out[self.out_pos..]))?;
+            if n == 0 {
+                return Poll::Ready(Err(std::io::ErrorKind::WriteZero.into()));
+            }
+            self.out_pos += n;
+        }
+        self.out.clear();
+        self.out_pos = 0;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for EncryptWriter<W> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        data: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+        if !this.is_encrypted {
+            return Pin::new(&mut this.inner).poll_write(cx, data);
+        }
+        ready!(this.poll_write_out(cx))?;
+        // full segment is encrypted when more bytes come, so the last segment is never empty unless all are
+        if this.buf.len() == SEGMENT_SIZE {
+            let encryptor = this
+                .encryptor
+                .as_mut()
+                .ok_or_else(|| std::io::Error::other("write after shutdown"))?;
+            this.out = encryptor
+                .encrypt_next(this.buf.as_slice())
+                .map_err(|_| std::io::Error::other("failed to encrypt segment"))?;
+            this.buf.clear();
+            ready!(this.poll_write_out(cx))?;
+        }
+        let len = data.len().min(SEGMENT_SIZE - this.buf.len());
+        this.buf.extend_from_slice(&data[..len]);
+        Poll::Ready(Ok(len))
+    }
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_out(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_out(cx))?;
+        if let Some(encryptor) = this.encryptor.take() {
+            this.out = encryptor
+                .encrypt_last(this.buf.as_slice())
+                .map_err(|_| std::io::Error::other("failed to encrypt last segment"))?;
+            this.buf.clear();
+            ready!(this.poll_write_out(cx))?;
+        }
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncWriteExt;
+
+    const KEY: &str = "MDEyMzQ1Njc4OWFiY2RlZjAxMjM0NTY3ODlhYmNkZWY=";
+
+    #[tokio::test]
+    async fn test_encrypt_writer() {
+        let key = Key::new("backup-2024".to_owned(), KEY).unwrap();
+        for len in [0, 1, SEGMENT_SIZE, SEGMENT_SIZE + 1, 3 * SEGMENT_SIZE + 7] {
+            let data = (0..len).map(|i| i as u8).collect::<Vec<_>>();
+            let mut writer = EncryptWriter::new(Vec::new(), Some(&key));
+            writer.write_all(&data).await.unwrap();
+            writer.shutdown().await.unwrap();
+            let encrypted = writer.into_inner();
+            assert_eq!(key_id(&encrypted).as_deref(), Some("backup-2024"));
+            assert_eq!(decrypt(encrypted.clone(), Some(&key)).unwrap(), data);
+            assert_eq!(decrypt(encrypt(&data, &key), Some(&key)).unwrap(), data);
+
+            // truncated or tampered object does not decrypt
+            let truncated = encrypted[..encrypted.len() - 1].to_vec();
+            assert!(decrypt(truncated, Some(&key)).is_err());
+            let mut tampered = encrypted.clone();
+            *tampered.last_mut().unwrap() ^= 1;
+            assert!(decrypt(tampered, Some(&key)).is_err());
+            assert!(decrypt(encrypted, None).is_err());
+        }
+        let other = Key::new("other".to_owned(), KEY).unwrap();
+        assert!(decrypt(encrypt(b"[]", &key), Some(&other)).is_err());
+        assert_eq!(decrypt(b"[]".to_vec(), Some(&key)).unwrap(), b"[]");
+    }
+}
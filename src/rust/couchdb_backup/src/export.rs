@@ -0,0 +1,519 @@
+#[allow(unused_imports)]
+use anyhow::{anyhow, bail, Context, Error, Result};
+
+use super::*;
+use serde_json::Value;
+
+/// How attachment bodies are backed up
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Attachments {
+    /// Inline as base64 `data` of `_attachments` (`_bulk_get?attachments=true`), like couchdb_backup.sh does
+    #[default]
+    Inline,
+    /// As separate S3 objects `${db_prefix}/attachments/${doc_id}/${name}`, `_attachments` keep stubs with `key` relative to `${db_prefix}`
+    Separate,
+}
+
+/// Prefix of ids of design docs: they are backed up apart from chunks, see `design_docs`
+pub const DESIGN_PREFIX: &str = "_design/";
+
+/// Percent encodes `s` to be used as a segment of CouchDB url path
+pub fn url_encode(s: &str) -> String {
+    percent_encoding::utf8_percent_encode(s, percent_encoding::NON_ALPHANUMERIC).to_string()
+}
+
+/// How many docs are requested by one `_bulk_get`: docs are passed on one by one, so memory is bounded by this
+const BULK_GET_BATCH: usize = 100;
+
+/// What `fetch_chunks` sends
+#[derive(Debug)]
+pub enum Fetched {
+    Doc(Value),
+    /// All docs of current chunk are sent
+    ChunkEnd,
+}
+
+/// Reads `_all_docs` page by page (`chunk` ids per page, all at once if `chunk` is not set) after `startkey` (from the
+/// first if not set), fetches docs of each page via `_bulk_get` by `BULK_GET_BATCH` and sends them to `tx` one by one,
+/// then `Fetched::ChunkEnd`;
+/// `db_path` is percent encoded name of database (`Database::name()`);
+/// `s3b` is used to upload attachments (encrypted with `key` if set) if they are stored separately;
+/// returns `_id` of the last doc sent if it stopped at `deadline` (or by Ctrl-C, see `delay::ctrl_c`) before all docs
+/// are sent
+#[allow(clippy::too_many_arguments)]
+pub async fn fetch_chunks(
+    client: &couch_rs::Client,
+    s3b: &s3_bucket::S3Bucket,
+    db_path: &str,
+    db_prefix: &str,
+    startkey: Option<String>,
+    chunk: Option<u64>,
+    attachments: Attachments,
+    key: Option<&encrypt::Key>,
+    deadline: Option<std::time::Instant>,
+    tx: tokio::sync::mpsc::Sender<Fetched>,
+) -> Result<Option<String>> {
+    let mut startkey = startkey;
+    loop {
+        let ids = all_docs_ids(client, db_path, chunk, startkey.as_deref()).await?;
+        let Some(last) = ids.last().cloned() else {
+            break;
+        };
+        let page_len = ids.len();
+        let ids = ids
+            .into_iter()
+            .filter(|id| !id.starts_with(DESIGN_PREFIX))
+            .collect::<Vec<_>>();
+        let mut sent = 0;
+        for ids in ids.chunks(BULK_GET_BATCH) {
+            let docs = bulk_get(client, db_path, ids, attachments == Attachments::Inline).await?;
+            for mut doc in docs {
+                if attachments == Attachments::Separate {
+                    upload_attachments(client, db_path, s3b, db_prefix, key, &mut doc).await?;
+                }
+                if tx.send(Fetched::Doc(doc)).await.is_err() {
+                    bail!("docs of {db_path:?} are not consumed anymore");
+                }
+                sent += 1;
+            }
+        }
+        // page of design docs only makes no chunk
+        if sent > 0 && tx.send(Fetched::ChunkEnd).await.is_err() {
+            bail!("docs of {db_path:?} are not consumed anymore");
+        }
+        match chunk {
+            Some(chunk) if page_len as u64 >= chunk => {
+                if is_past(deadline) || delay::is_interrupted() {
+                    return Ok(Some(last));
+                }
+                startkey = Some(last)
+            }
+            _ => break,
+        }
+    }
+    Ok(None)
+}
+
+fn is_past(deadline: Option<std::time::Instant>) -> bool {
+    deadline.is_some_and(|deadline| std::time::Instant::now() >= deadline)
+}
+
+/// Reads `_changes` since `since` by `BULK_GET_BATCH` with docs (attachments inline if `attachments` is `Inline`) and
+/// sends them to `tx` one by one, deleted ones as tombstones `{_id, _rev, _deleted: true}`, then `Fetched::ChunkEnd`
+/// after each `chunk` docs (after all of them if `chunk` is not set); if it is past `deadline` (or Ctrl-C is pressed) at
+/// the end of a page, ends current chunk and returns `last_seq` of the page; see `fetch_chunks` for the rest
+#[allow(clippy::too_many_arguments)]
+pub async fn fetch_changes(
+    client: &couch_rs::Client,
+    s3b: &s3_bucket::S3Bucket,
+    db_path: &str,
+    db_prefix: &str,
+    since: &str,
+    chunk: Option<u64>,
+    attachments: Attachments,
+    key: Option<&encrypt::Key>,
+    deadline: Option<std::time::Instant>,
+    tx: tokio::sync::mpsc::Sender<Fetched>,
+) -> Result<Option<String>> {
+    #[derive(Deserialize)]
+    struct Changes {
+        results: Vec<Change>,
+        last_seq: Value,
+    }
+    #[derive(Deserialize)]
+    struct Change {
+        id: String,
+        #[serde(default)]
+        deleted: bool,
+        #[serde(default)]
+        changes: Vec<Rev>,
+        doc: Option<Value>,
+    }
+    #[derive(Deserialize)]
+    struct Rev {
+        rev: String,
+    }
+    let mut in_chunk = 0;
+    let mut since = since.to_owned();
+    loop {
+        let mut opts = std::collections::HashMap::new();
+        opts.insert("since".to_owned(), since.clone());
+        opts.insert("include_docs".to_owned(), "true".to_owned());
+        opts.insert("style".to_owned(), "main_only".to_owned());
+        opts.insert("limit".to_owned(), BULK_GET_BATCH.to_string());
+        if attachments == Attachments::Inline {
+            opts.insert("attachments".to_owned(), "true".to_owned());
+        }
+        let changes = client
+            .req(
+                reqwest::Method::GET,
+                &format!("{db_path}/_changes"),
+                Some(&opts),
+            )
+            .send()
+            .await
+            .and_then(|resp| resp.error_for_status())
+            .map_err(|err| anyhow!("failed to get {db_path:?}/_changes since {since:?}: {err}"))?
+            .json::<Changes>()
+            .await
+            .map_err(|err| anyhow!("failed to parse {db_path:?}/_changes: {err}"))?;
+        let is_last = changes.results.len() < BULK_GET_BATCH;
+        for change in changes.results {
+            if change.id.starts_with(DESIGN_PREFIX) {
+                continue;
+            }
+            let mut doc = match (change.doc, change.changes.first()) {
+                (Some(doc), _) => doc,
+                (None, Some(Rev { rev })) if change.deleted => {
+                    serde_json::json!({ "_id": change.id, "_rev": rev, "_deleted": true })
+                }
+                _ => {
+                    warn!("no doc of change of {:?} of {db_path:?}", change.id);
+                    continue;
+                }
+            };
+            if attachments == Attachments::Separate {
+                upload_attachments(client, db_path, s3b, db_prefix, key, &mut doc).await?;
+            }
+            in_chunk += 1;
+            if tx.send(Fetched::Doc(doc)).await.is_err() {
+                bail!("docs of {db_path:?} are not consumed anymore");
+            }
+            if chunk.is_some_and(|chunk| in_chunk >= chunk) {
+                in_chunk = 0;
+                if tx.send(Fetched::ChunkEnd).await.is_err() {
+                    bail!("docs of {db_path:?} are not consumed anymore");
+                }
+            }
+        }
+        // `last_seq` is string since CouchDB 2.0, number before
+        since = match changes.last_seq {
+            Value::String(s) => s,
+            last_seq => last_seq.to_string(),
+        };
+        let is_stopped = !is_last && (is_past(deadline) || delay::is_interrupted());
+        if is_last || is_stopped {
+            if in_chunk > 0 && tx.send(Fetched::ChunkEnd).await.is_err() {
+                bail!("docs of {db_path:?} are not consumed anymore");
+            }
+            return Ok(is_stopped.then_some(since));
+        }
+    }
+}
+
+async fn get_json(client: &couch_rs::Client, path: &str) -> Result<Value> {
+    client
+        .req(reqwest::Method::GET, path, None)
+        .send()
+        .await
+        .and_then(|resp| resp.error_for_status())
+        .map_err(|err| anyhow!("failed to get {path:?}: {err}"))?
+        .json::<Value>()
+        .await
+        .map_err(|err| anyhow!("failed to parse {path:?}: {err}"))
+}
+
+/// `GET /{db}`
+pub async fn db_meta(client: &couch_rs::Client, db_path: &str) -> Result<manifest::DbMeta> {
+    Ok(manifest::DbMeta::of_db_info(
+        &get_json(client, db_path).await?,
+    ))
+}
+
+/// `GET /{db}/_security`
+pub async fn security(client: &couch_rs::Client, db_path: &str) -> Result<Value> {
+    get_json(client, &format!("{db_path}/_security")).await
+}
+
+/// Design docs with attachments inline (`_design_docs?include_docs=true&attachments=true`)
+pub async fn design_docs(client: &couch_rs::Client, db_path: &str) -> Result<Vec<Value>> {
+    #[derive(Deserialize)]
+    struct DesignDocs {
+        rows: Vec<Row>,
+    }
+    #[derive(Deserialize)]
+    struct Row {
+        doc: Option<Value>,
+    }
+    let path = format!("{db_path}/_design_docs");
+    let opts = [("include_docs", "true"), ("attachments", "true")]
+        .into_iter()
+        .map(|(name, value)| (name.to_owned(), value.to_owned()))
+        .collect();
+    let design_docs = client
+        .req(reqwest::Method::GET, &path, Some(&opts))
+        .send()
+        .await
+        .and_then(|resp| resp.error_for_status())
+        .map_err(|err| anyhow!("failed to get {path:?}: {err}"))?
+        .json::<DesignDocs>()
+        .await
+        .map_err(|err| anyhow!("failed to parse {path:?}: {err}"))?;
+    Ok(design_docs
+        .rows
+        .into_iter()
+        .filter_map(|row| row.doc)
+        .collect())
+}
+
+/// Ids of `_all_docs` after `startkey` (from the first if not set), `limit` of them (all if not set)
+pub async fn all_docs_ids(
+    client: &couch_rs::Client,
+    db_path: &str,
+    limit: Option<u64>,
+    startkey: Option<&str>,
+) -> Result<Vec<String>> {
+    #[derive(Deserialize)]
+    struct Row {
+        id: String,
+    }
+    #[derive(Deserialize)]
+    struct AllDocs {
+        rows: Vec<Row>,
+    }
+    let mut opts = std::collections::HashMap::new();
+    if let Some(limit) = limit {
+        opts.insert("limit".to_owned(), limit.to_string());
+    }
+    if let Some(startkey) = startkey {
+        opts.insert("startkey".to_owned(), serde_json::to_string(startkey)?);
+        opts.insert("skip".to_owned(), "1".to_owned());
+    }
+    let all_docs = client
+        .req(
+            reqwest::Method::GET,
+            &format!("{db_path}/_all_docs"),
+            Some(&opts),
+        )
+        .send()
+        .await
+        .and_then(|resp| resp.error_for_status())
+        .map_err(|err| anyhow!("failed to get {db_path:?}/_all_docs: {err}"))?
+        .json::<AllDocs>()
+        .await
+        .map_err(|err| anyhow!("failed to parse {db_path:?}/_all_docs: {err}"))?;
+    Ok(all_docs.rows.into_iter().map(|row| row.id).collect())
+}
+
+/// Like `send_save_command()` of couchdb_backup.sh: `_bulk_get?attachments=true`
+async fn bulk_get(
+    client: &couch_rs::Client,
+    db_path: &str,
+    ids: &[String],
+    attachments: bool,
+) -> Result<Vec<Value>> {
+    #[derive(Deserialize)]
+    struct BulkGet {
+        results: Vec<BulkGetResult>,
+    }
+    #[derive(Deserialize)]
+    struct BulkGetResult {
+        id: String,
+        docs: Vec<BulkGetDoc>,
+    }
+    #[derive(Deserialize)]
+    #[serde(rename_all = "lowercase")]
+    enum BulkGetDoc {
+        Ok(Value),
+        Error(Value),
+    }
+    let mut opts = std::collections::HashMap::new();
+    if attachments {
+        opts.insert("attachments".to_owned(), "true".to_owned());
+    }
+    let body = serde_json::json!({
+        "docs": ids.iter().map(|id| serde_json::json!({ "id": id })).collect::<Vec<_>>()
+    });
+    let bulk_get = client
+        .req(
+            reqwest::Method::POST,
+            &format!("{db_path}/_bulk_get"),
+            Some(&opts),
+        )
+        .header(reqwest::header::ACCEPT, "application/json")
+        .json(&body)
+        .send()
+        .await
+        .and_then(|resp| resp.error_for_status())
+        .map_err(|err| anyhow!("failed to post {db_path:?}/_bulk_get: {err}"))?
+        .json::<BulkGet>()
+        .await
+        .map_err(|err| anyhow!("failed to parse {db_path:?}/_bulk_get: {err}"))?;
+    let mut ret = vec![];
+    for result in bulk_get.results {
+        for doc in result.docs {
+            match doc {
+                BulkGetDoc::Ok(doc) => ret.push(doc),
+                // deleted since its id was read
+                BulkGetDoc::Error(err)
+                    if err.get("error").and_then(|error| error.as_str()) == Some("not_found") =>
+                {
+                    debug!("doc {:?} of {db_path:?} is not found: {err}", result.id)
+                }
+                BulkGetDoc::Error(err) => {
+                    bail!("failed to get doc {:?} of {db_path:?}: {err}", result.id)
+                }
+            }
+        }
+    }
+    Ok(ret)
+}
+
+/// Key of attachment relative to `${db_prefix}`
+pub fn attachment_key(doc_id: &str, name: &str) -> String {
+    format!("attachments/{}/{}", url_encode(doc_id), url_encode(name))
+}
+
+/// Uploads bodies of attachments stubs of `doc` to S3 (encrypted with `encryption_key` if set) and sets `key` of each stub
+async fn upload_attachments(
+    client: &couch_rs::Client,
+    db_path: &str,
+    s3b: &s3_bucket::S3Bucket,
+    db_prefix: &str,
+    encryption_key: Option<&encrypt::Key>,
+    doc: &mut Value,
+) -> Result<()> {
+    let Some(doc_id) = doc
+        .get("_id")
+        .and_then(|id| id.as_str())
+        .map(|s| s.to_owned())
+    else {
+        return Ok(());
+    };
+    let Some(attachments) = doc
+        .get_mut("_attachments")
+        .and_then(|attachments| attachments.as_object_mut())
+    else {
+        return Ok(());
+    };
+    for (name, attachment) in attachments.iter_mut() {
+        let path = format!("{db_path}/{}/{}", url_encode(&doc_id), url_encode(name));
+        let body = client
+            .req(reqwest::Method::GET, &path, None)
+            .send()
+            .await
+            .and_then(|resp| resp.error_for_status())
+            .map_err(|err| anyhow!("failed to get attachment {path:?}: {err}"))?
+            .bytes()
+            .await
+            .map_err(|err| anyhow!("failed to read attachment {path:?}: {err}"))?;
+        let key = attachment_key(&doc_id, name);
+        let (body, content_type) = match encryption_key {
+            Some(encryption_key) => (
+                encrypt::encrypt(&body, encryption_key).into(),
+                Some("application/octet-stream".to_owned()),
+            ),
+            None => (
+                body,
+                attachment
+                    .get("content_type")
+                    .and_then(|content_type| content_type.as_str())
+                    .map(|s| s.to_owned()),
+            ),
+        };
+        let content_length = body.len() as i64;
+        let object_to_upload = s3_bucket::ObjectToUploadBuilder::from_bytes(body)
+            .content_length(Some(content_length))
+            .content_type(content_type)
+            .build();
+        s3b.upload(format!("{db_prefix}/{key}"), object_to_upload)
+            .await
+            .map_err(|err| anyhow!("failed to upload attachment {path:?}: {err}"))?;
+        if let Some(attachment) = attachment.as_object_mut() {
+            attachment.insert("key".to_owned(), key.into());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    /// CouchDB stub answering `i`-th request with `bodies[i]`
+    async fn stub(bodies: Vec<Value>) -> couch_rs::Client {
+        let mut bodies = bodies.into_iter();
+        let url = crate::stub::serve(move |_| match bodies.next() {
+            Some(body) => (200, body.to_string()),
+            None => (
+                500,
+                serde_json::json!({ "error": "unexpected" }).to_string(),
+            ),
+        })
+        .await;
+        couch_rs::Client::new(&url, "admin", "secret").unwrap()
+    }
+
+    /// Ids of docs sent by `fetch_chunks` of 2 docs per chunk from stub answering `bodies`, "end" for `ChunkEnd`
+    async fn fetch_chunks_of(bodies: Vec<Value>) -> Result<Vec<String>> {
+        let client = stub(bodies).await;
+        let s3b = s3_bucket::S3BucketBuilder::new("bucket".to_owned())
+            .region(s3_bucket::Region::UsEast1)
+            .provider(s3_bucket::StaticProvider::new_minimal(
+                "token".to_owned(),
+                "secret".to_owned(),
+            ))
+            .build()
+            .unwrap();
+        let (tx, mut rx) = tokio::sync::mpsc::channel(10);
+        let ret = fetch_chunks(
+            &client,
+            &s3b,
+            "db",
+            "prefix/db",
+            None,
+            Some(2),
+            Attachments::Inline,
+            None,
+            None,
+            tx,
+        )
+        .await?;
+        assert_eq!(ret, None);
+        let mut fetched = vec![];
+        while let Some(item) = rx.recv().await {
+            fetched.push(match item {
+                Fetched::Doc(doc) => doc["_id"].as_str().unwrap().to_owned(),
+                Fetched::ChunkEnd => "end".to_owned(),
+            });
+        }
+        Ok(fetched)
+    }
+
+    fn rows(ids: &[&str]) -> Value {
+        serde_json::json!({
+            "rows": ids.iter().map(|id| serde_json::json!({ "id": id })).collect::<Vec<_>>()
+        })
+    }
+
+    #[tokio::test]
+    async fn test_fetch_chunks() {
+        // the first page has design docs only, doc "d" of the second is deleted since
+        let fetched = fetch_chunks_of(vec![
+            rows(&["_design/a", "_design/b"]),
+            rows(&["c", "d"]),
+            serde_json::json!({
+                "results": [
+                    { "id": "c", "docs": [{ "ok": { "_id": "c", "_rev": "1-a" } }] },
+                    { "id": "d", "docs": [{ "error": { "id": "d", "rev": "undefined", "error": "not_found", "reason": "deleted" } }] },
+                ]
+            }),
+            rows(&[]),
+        ])
+        .await
+        .unwrap();
+        assert_eq!(fetched, vec!["c", "end"]);
+
+        // any other error fails it
+        let ret = fetch_chunks_of(vec![
+            rows(&["c"]),
+            serde_json::json!({
+                "results": [
+                    { "id": "c", "docs": [{ "error": { "id": "c", "rev": "1-a", "error": "internal_server_error", "reason": "timeout" } }] },
+                ]
+            }),
+        ])
+        .await;
+        assert!(ret.is_err());
+    }
+}
@@ -0,0 +1,154 @@
+#[allow(unused_imports)]
+use anyhow::{anyhow, bail, Context, Error, Result};
+
+use super::*;
+use serde_json::Value;
+use std::path::Path;
+
+/// Prefix of env vars overriding config: `COUCHDB_BACKUP__DATABASE__URL` for `database.url`
+pub const ENV_PREFIX: &str = "COUCHDB_BACKUP";
+
+/// Input of scheduled EventBridge rule: `{"mode": "weekly", "databases": ["^account/"]}`; the whole event or its
+/// `detail`
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct BackupEvent {
+    pub mode: Mode,
+    /// Regexes of databases to back up instead of `databases` of task
+    pub databases: Option<Vec<String>>,
+    /// Months of `-YYYYMM` suffix of databases to back up, `YYYYMM` or `YYYYMM..YYYYMM`
+    pub month: Option<String>,
+    /// Overrides `on_failure`
+    pub on_failure: Option<report::FailurePolicy>,
+    /// Time budget, seconds; overrides budget of `handle`
+    pub budget: Option<u64>,
+    /// `continuation` of report of invocation stopped by time budget
+    pub resume: Option<String>,
+    /// Settings over config file and env vars, e.g. `{"task": {"weekly": {"delay": 0}}}`
+    pub config: Option<Value>,
+}
+
+impl BackupEvent {
+    /// Of payload of Lambda invocation: constant input of rule, or EventBridge event with it in `detail`
+    pub fn of_event(event: Value) -> Result<Self> {
+        let event = match event {
+            Value::Object(mut event) if event.contains_key("detail-type") => {
+                event.remove("detail").unwrap_or(Value::Null)
+            }
+            event => event,
+        };
+        serde_json::from_value(event).map_err(|err| anyhow!("failed to parse event: {err}"))
+    }
+}
+
+/// Settings of `config_path` (if it exists), `COUCHDB_BACKUP__*` env vars and `config` of event, each over the
+/// previous one
+pub fn settings_content(config_path: &Path, config: Option<&Value>) -> Result<SettingsContent> {
+    let mut builder = config::Config::builder()
+        .add_source(config::File::from(config_path).required(false))
+        .add_source(
+            config::Environment::with_prefix(ENV_PREFIX)
+                .prefix_separator("__")
+                .separator("__")
+                .try_parsing(true),
+        );
+    if let Some(config) = config {
+        builder = builder.add_source(config::File::from_str(
+            &serde_json::to_string(config)?,
+            config::FileFormat::Json,
+        ));
+    }
+    builder
+        .build()?
+        .try_deserialize()
+        .map_err(|err| anyhow!("failed to load settings: {err}"))
+}
+
+/// Handles Lambda invocation: loads settings (see `settings_content`), backs up within `budget` of event (`budget` if
+/// not set) and returns report; invoke it again with `continuation` of report as `resume` if it is set
+pub async fn handle(
+    event: Value,
+    config_path: &Path,
+    budget: Option<std::time::Duration>,
+) -> Result<report::BackupReport> {
+    let BackupEvent {
+        mode,
+        databases,
+        month,
+        on_failure,
+        budget: event_budget,
+        resume,
+        config,
+    } = BackupEvent::of_event(event)?;
+    let months = month.map(|month| month.parse()).transpose()?;
+    let content = settings_content(config_path, config.as_ref())?;
+    *(SETTINGS.write().unwrap()) = Some(Settings { content });
+    loki::start(&settings!(loki));
+    info!("will run {mode:?} backup by event");
+    let report = run(
+        mode,
+        RunArg {
+            months,
+            on_failure,
+            databases,
+            budget: event_budget.map(std::time::Duration::from_secs).or(budget),
+            resume,
+        },
+    )
+    .await;
+    // invocation is frozen once it returns
+    loki::flush().await;
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backup_event_of_event() {
+        let input = serde_json::json!({ "mode": "weekly", "databases": ["^account/"] });
+        let event = BackupEvent::of_event(input.clone()).unwrap();
+        assert_eq!(event.mode, Mode::Weekly);
+        assert_eq!(event.databases, Some(vec!["^account/".to_owned()]));
+        assert_eq!(event.month, None);
+
+        let scheduled = serde_json::json!({
+            "version": "0",
+            "id": "53dc4d37-cffa-4f76-80c9-8b7d4a4d2eaa",
+            "detail-type": "Scheduled Event",
+            "source": "aws.events",
+            "time": "2024-03-03T02:00:00Z",
+            "region": "eu-central-1",
+            "resources": ["arn:aws:events:eu-central-1:123456789012:rule/couchdb-backup-weekly"],
+            "detail": input,
+        });
+        assert_eq!(BackupEvent::of_event(scheduled).unwrap(), event);
+
+        assert!(BackupEvent::of_event(serde_json::json!({ "mode": "daily" })).is_err());
+        assert!(
+            BackupEvent::of_event(serde_json::json!({ "detail-type": "Scheduled Event" })).is_err()
+        );
+    }
+
+    #[test]
+    fn test_settings_content_of_event() {
+        let task = serde_json::json!({ "cron": "0 0 2 * * Sun", "databases": ["^a$"], "delay": 5 });
+        let config = serde_json::json!({
+            "database": { "url": "http://[::1]:5984", "login": "admin", "password": "secret" },
+            "task": { "weekly": task, "monthly": task },
+            "bucket": "s3://bucket/folder",
+            "prefix": "backup",
+            "suffix": "couchdb",
+            "loki": "http://[::1]:3100/loki/api/v1/push",
+        });
+        // monthly task misses `backup_only_previus`
+        assert!(settings_content(Path::new("/nonexistent.yaml"), Some(&config)).is_err());
+
+        let mut config = config;
+        config["task"]["monthly"]["backup_only_previus"] = true.into();
+        let content = settings_content(Path::new("/nonexistent.yaml"), Some(&config)).unwrap();
+        assert_eq!(content.bucket, "s3://bucket/folder");
+        assert_eq!(content.task.weekly.delay, 5);
+        assert!(content.task.monthly.backup_only_previus);
+    }
+}
@@ -2,19 +2,126 @@
 use anyhow::{anyhow, bail, Context, Error, Result};
 #[allow(unused_imports)]
 use tracing::{debug, error, info, span, trace, warn, Level};
+use tracing::{Instrument, Span};
 
 use serde::{Deserialize, Serialize};
 
+/// `settings!(task.weekly.$field)` or `settings!(task.monthly.$field)` depending on `mode`
+macro_rules! task_settings {
+    ($mode:expr, $field:ident) => {
+        match $mode {
+            Mode::Weekly => settings!(task.weekly.$field).clone(),
+            Mode::Monthly => settings!(task.monthly.$field).clone(),
+        }
+    };
+}
+
+pub mod bucket;
+pub mod calendar;
+pub mod delay;
+pub mod encode;
+pub mod encrypt;
+pub mod export;
+pub mod lambda;
+pub mod loki;
+pub mod manifest;
+pub mod metrics;
+pub mod month;
+pub mod pipeline;
+pub mod report;
+pub mod restore;
+pub mod resume;
+pub mod schedule;
+#[cfg(test)]
+mod stub;
+pub mod verify;
+pub mod views;
+
 use common_macros::*;
 declare_settings! {
     database: SettingsDatabase,
     task: SettingsTask,
     bucket: String, //“s3://data.example.com/folder”
-    token: String, //“XXXXXXXXX”
-    secret: String, //“YYYYYYYYYY”
+    token: Option<String>, //“XXXXXXXXX”, credentials are taken from env, ~/.aws, container or instance IAM role if not set
+    secret: Option<String>, //“YYYYYYYYYY”
     prefix: String, //“backup/ippbx”
     suffix: String, // “couchdb”
-    loki: String, // “http://syslog-west.example.com:3100/loki/api/v1/push”
+    loki: String, // “http://syslog-west.example.com:3100/loki/api/v1/push”, logs are not pushed if empty (see `loki`)
+    on_failure: Option<report::FailurePolicy>, // “continue” (default) or “fail_fast”
+    s3: Option<SettingsS3>,
+    encryption: Option<SettingsEncryption>,
+    views: Option<SettingsViews>,
+    state_file: Option<String>, // local file of progress of backup stopped by time budget, S3 object if not set (see `resume`)
+    metrics: Option<SettingsMetrics>,
+}
+
+/// Prometheus metrics of backup runs, see `metrics`
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SettingsMetrics {
+    /// Where daemon serves `/metrics`, e.g. “[::]:9185”
+    listen: Option<String>,
+    /// Pushgateway which metrics are pushed to after each run, e.g. “http://pushgateway:9091”
+    pushgateway: Option<String>,
+}
+
+/// Client-side encryption of chunks and attachments, see `encrypt`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SettingsEncryption {
+    /// Stored in each encrypted object and manifest, so the key can be found to decrypt them, e.g. “backup-2024”
+    key_id: String,
+    /// File of base64 encoded 32 bytes key (`openssl rand -base64 32`)
+    key_file: Option<String>,
+    /// Env var of base64 encoded 32 bytes key, instead of `key_file`
+    key_env: Option<String>,
+}
+
+/// Index build after restore, see `views::rebuild`
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SettingsViews {
+    /// Views to build as `ddoc/view`, e.g. “cdrs/crossbar_listing”; all views of restored database if not set
+    list: Option<Vec<String>>,
+    /// How many views are built at once (2 by default)
+    concurrency: Option<usize>,
+    /// How often `_active_tasks` is polled while index is built, seconds (10 by default)
+    poll: Option<u64>,
+    /// Restore fails if a view fails to build; it is only logged if not set
+    #[serde(default)]
+    fatal: bool,
+}
+
+impl SettingsViews {
+    pub fn concurrency(&self) -> usize {
+        self.concurrency.unwrap_or(2).max(1)
+    }
+    pub fn poll(&self) -> u64 {
+        self.poll.unwrap_or(10).max(1)
+    }
+}
+
+/// Where `bucket` is; `S3_REGION_NAME`/`S3_REGION_ENDPOINT` env vars (Yandex Object Storage by default) if not set
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SettingsS3 {
+    /// AWS region name, e.g. “eu-central-1”, or name of region of custom `endpoint`
+    region: Option<String>,
+    /// Custom endpoint, e.g. “https://storage.yandexcloud.net”, “http://[::1]:9000” for local MinIO
+    endpoint: Option<String>,
+    /// “path” (default) or “virtual_host”
+    #[serde(default)]
+    addressing: s3_bucket::Addressing,
+    /// Use dual-stack (IPv4 and IPv6) endpoint of AWS `region`
+    #[serde(default)]
+    dual_stack: bool,
+    /// Part of multipart upload of chunks, MiB (at least 5, 8 by default); memory used is about
+    /// `(2 * concurrency + 1) * part_size`
+    part_size: Option<usize>,
+    /// How many parts of a chunk are uploaded at once (4 by default)
+    concurrency: Option<usize>,
+    /// How many times upload (of object, or of part of chunk) is retried on network error, 5xx or 429 (3 by default)
+    retries: Option<u32>,
+    /// Server-side encryption of uploaded objects: “aes256” (SSE-S3) or “aws_kms” (SSE-KMS)
+    server_side_encryption: Option<s3_bucket::ServerSideEncryption>,
+    /// KMS key of “aws_kms”, default KMS key of bucket if not set
+    sse_kms_key_id: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -35,7 +142,15 @@ pub struct SettingsTaskWeekly {
     cron: String,
     databases: Vec<String>,
     delay: u64,
+    adaptive_delay: Option<SettingsAdaptiveDelay>,
     chunk: Option<u64>,
+    #[serde(default)]
+    attachments: export::Attachments,
+    #[serde(default)]
+    compression: encode::Compression, // codec “gzip” (default), “zstd”, “xz” or “none” and its level
+    incremental: Option<SettingsIncremental>, // full backups only if not set
+    #[serde(default)]
+    unchanged: pipeline::Unchanged, // “backup” (default), “pointer” or “skip” database not changed since the last backup
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -43,48 +158,337 @@ pub struct SettingsTaskMonthly {
     cron: String,
     databases: Vec<String>,
     delay: u64,
+    adaptive_delay: Option<SettingsAdaptiveDelay>,
     chunk: Option<u64>,
+    #[serde(default)]
+    attachments: export::Attachments,
+    #[serde(default)]
+    compression: encode::Compression, // codec “gzip” (default), “zstd”, “xz” or “none” and its level
+    incremental: Option<SettingsIncremental>, // full backups only if not set
+    #[serde(default)]
+    unchanged: pipeline::Unchanged, // “backup” (default), “pointer” or “skip” database not changed since the last backup
     backup_only_previus: bool,
 }
 
-#[derive(Clone, Copy, Debug)]
+/// Back up only docs changed since the last backup (`_changes`), see `manifest::base`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SettingsIncremental {
+    /// Full backup is made after this many deltas (6 by default); restore applies full backup and its deltas
+    max_deltas: Option<usize>,
+}
+
+impl SettingsIncremental {
+    pub fn max_deltas(&self) -> usize {
+        self.max_deltas.unwrap_or(6)
+    }
+}
+
+/// Lengthens `delay` while CouchDB is under load, see `delay::pause`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SettingsAdaptiveDelay {
+    /// CouchDB is under load if it runs more `_active_tasks` than this
+    active_tasks: Option<usize>,
+    /// CouchDB is under load if `_active_tasks` responds slower than this, ms
+    latency: Option<u64>,
+    /// Upper bound of lengthened delay, seconds
+    max_delay: u64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum Mode {
     Weekly,
     Monthly,
 }
 
-pub async fn run(mode: Mode) -> Result<()> {
-    let start = std::time::Instant::now();
+impl std::fmt::Display for Mode {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Weekly => "weekly",
+            Self::Monthly => "monthly",
+        })
+    }
+}
+
+/// Format of console log
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum LogFormat {
+    /// Line per event, fields of spans inline
+    #[default]
+    Pretty,
+    /// JSON object per event with `span` and `spans`
+    Json,
+}
+
+/// Console filter if `RUST_LOG` is not set
+const DEFAULT_LOG_FILTER: &str = "warn,couchdb_backup=info,s3_bucket=info";
 
-    let regex_list = match mode {
-        Mode::Weekly => settings!(task.weekly.databases).clone(),
-        Mode::Monthly => settings!(task.monthly.databases).clone(),
+/// Installs global subscriber: stderr in `format` (stdout is left to report) filtered by `RUST_LOG` (info of this crate and `s3_bucket`,
+/// warnings of the rest if not set), and `loki::layer()` (the same), which pushes once `loki::start` is called
+pub fn init_tracing(format: LogFormat) {
+    use tracing_subscriber::layer::SubscriberExt;
+    use tracing_subscriber::Layer;
+    let loki = loki::layer().with_filter(
+        tracing_subscriber::filter::Targets::new()
+            .with_default(Level::WARN)
+            .with_target(env!("CARGO_CRATE_NAME"), Level::INFO)
+            .with_target("s3_bucket", Level::INFO),
+    );
+    let console = match format {
+        LogFormat::Pretty => tracing_subscriber::fmt::layer()
+            .with_writer(std::io::stderr)
+            .boxed(),
+        LogFormat::Json => tracing_subscriber::fmt::layer()
+            .with_writer(std::io::stderr)
+            .json()
+            .with_current_span(true)
+            .with_span_list(true)
+            .boxed(),
+    };
+    let filter = tracing_subscriber::EnvFilter::try_from_default_env()
+        .unwrap_or_else(|_| tracing_subscriber::EnvFilter::new(DEFAULT_LOG_FILTER));
+    let subscriber = tracing_subscriber::registry()
+        .with(console.with_filter(filter))
+        .with(loki);
+    tracing::subscriber::set_global_default(subscriber).expect("setting default subscriber failed");
+}
+
+/// Connects to `database.url` (or `url` if set) as `database.login`
+pub fn couchdb_client(url: Option<&str>) -> Result<(couch_rs::Client, String)> {
+    let SettingsDatabase {
+        url: uri,
+        login: username,
+        password,
+    } = settings!(database).clone();
+    let uri = url.map(|s| s.to_owned()).unwrap_or(uri);
+    Ok((
+        couch_rs::Client::new(&uri, &username, &password).map_err(|err| {
+            anyhow!("failed establish connection to {uri:?} as user {username:?}: {err}")
+        })?,
+        uri,
+    ))
+}
+
+#[derive(Debug, Default)]
+pub struct RunArg {
+    /// Months of `-YYYYMM` suffix of databases to back up; overrides `backup_only_previus`
+    pub months: Option<month::MonthRange>,
+    /// Overrides `on_failure`
+    pub on_failure: Option<report::FailurePolicy>,
+    /// Regexes of databases to back up; overrides `databases` of task
+    pub databases: Option<Vec<String>>,
+    /// Backup stops once it is spent (after the current chunk) and reports `continuation`; needs `chunk` of task, as
+    /// database without it is one chunk
+    pub budget: Option<std::time::Duration>,
+    /// `continuation` of report of stopped backup to go on with; databases left are taken from it
+    pub resume: Option<String>,
+}
+
+/// Backs up databases of `mode` in span `run` (fields `mode`, `duration_ms`), each in span `db` (see
+/// `pipeline::backup_db`); records `metrics` of it
+pub async fn run(mode: Mode, arg: RunArg) -> Result<report::BackupReport> {
+    let span = tracing::info_span!("run", mode = %mode, duration_ms = tracing::field::Empty);
+    async move {
+        let start = std::time::Instant::now();
+        let upload_retries = s3_bucket::upload_retries();
+        let report = run_backup(mode, arg).await;
+        metrics::record(
+            mode,
+            report.as_ref().ok(),
+            std::time::Instant::now().duration_since(start),
+            s3_bucket::upload_retries() - upload_retries,
+        )
+        .await;
+        report
     }
-    .into_iter()
-    .filter_map(|s| {
-        regex::Regex::new(&s)
-            .map_err(|err| eprintln!("failed Regex::new({s:?}: {err})"))
-            .ok()
-    })
-    .collect::<Vec<_>>();
-    if regex_list.is_empty() {
-        bail!("regex_list.is_empty");
+    .instrument(span)
+    .await
+}
+
+async fn run_backup(mode: Mode, arg: RunArg) -> Result<report::BackupReport> {
+    let start = std::time::Instant::now();
+    let interrupt = delay::ctrl_c();
+    // budget is checked between chunks only
+    if arg.budget.is_some() && task_settings!(mode, chunk).is_none() {
+        bail!("time budget of {mode} backup needs `task.{mode}.chunk`: without it every database is one chunk");
     }
 
-    let (client, uri) = {
-        let SettingsDatabase {
-            url: uri,
-            login: username,
-            password,
-        } = settings!(database).clone();
-        (
-            couch_rs::Client::new(&uri, &username, &password).map_err(|err| {
-                anyhow!("failed establish connection to {uri:?} as user {username:?}: {err}")
-            })?,
-            uri,
-        )
+    let s3b = bucket::s3_bucket()?;
+    s3b.check()
+        .await
+        .map_err(|err| anyhow!("S3 is not ready for {mode:?} backup: {err}"))?;
+    let key = encrypt::key()?;
+    if let Some(key) = key.as_ref() {
+        info!(
+            "chunks and attachments will be encrypted with key {:?}",
+            key.id()
+        );
+    }
+
+    let (client, uri) = couchdb_client(None)?;
+
+    let deadline = arg.budget.map(|budget| start + budget);
+    let (db_list, mut progress) = match arg.resume.as_deref() {
+        Some(token) => {
+            let state = resume::load(&s3b, token).await?;
+            if state.mode != mode {
+                bail!(
+                    "{token:?} is to resume {:?} backup, not {mode:?}",
+                    state.mode
+                );
+            }
+            info!(
+                "will resume {mode:?} backup stopped at {}, {} database(s) left{}",
+                state.saved_at,
+                state.databases.len(),
+                state
+                    .db
+                    .as_ref()
+                    .map(|db| format!(", db {:?} from chunk {:03}", db.db_name, db.chunks.len()))
+                    .unwrap_or_default(),
+            );
+            (state.databases, state.db)
+        }
+        None => (
+            select(&client, &uri, mode, arg.databases, arg.months).await?,
+            None,
+        ),
     };
+    let delay = std::time::Duration::from_secs(task_settings!(mode, delay));
+    let adaptive_delay = task_settings!(mode, adaptive_delay);
+    let policy = arg.on_failure.or(settings!(on_failure)).unwrap_or_default();
+    let mut report = report::BackupReport::new(mode, policy);
+    let db_count = db_list.len();
+    let mut db_list = db_list.into_iter();
+    let mut is_first = true;
+    // databases left and progress of the first of them if backup is stopped by time budget or Ctrl-C
+    let mut suspended: Option<(Vec<String>, Option<resume::DbProgress>)> = None;
+    while let Some(db_name) = db_list.next() {
+        if !is_first {
+            if let Err(err) =
+                delay::pause(&client, delay, adaptive_delay.as_ref(), &interrupt).await
+            {
+                if !err.is::<delay::Interrupted>() {
+                    return Err(err);
+                }
+            }
+        }
+        if interrupt.is_cancelled()
+            || !is_first && deadline.is_some_and(|deadline| std::time::Instant::now() >= deadline)
+        {
+            suspended = Some((std::iter::once(db_name).chain(db_list).collect(), None));
+            break;
+        }
+        is_first = false;
+        let db_progress = progress.take().filter(|db| db.db_name == db_name);
+        let mut db_result = pipeline::backup_db(
+            &client,
+            &s3b,
+            key.as_ref(),
+            mode,
+            &db_name,
+            deadline,
+            db_progress,
+        )
+        .instrument(pipeline::db_span(&db_name))
+        .await;
+        if let Some(db_progress) = db_result.progress.take() {
+            suspended = Some((
+                std::iter::once(db_name).chain(db_list).collect(),
+                Some(db_progress),
+            ));
+            break;
+        }
+        let is_ok = db_result.is_ok();
+        if db_result.skipped {
+            report.skipped.push(db_result.db_name);
+        } else {
+            report.databases.push(db_result);
+        }
+        if !is_ok && policy == report::FailurePolicy::FailFast {
+            report.not_processed.extend(db_list);
+            break;
+        }
+    }
+
+    if let Some((databases, db)) = suspended {
+        report.interrupted = interrupt.is_cancelled();
+        let state = resume::State {
+            mode,
+            databases: databases.clone(),
+            db,
+            saved_at: chrono::Utc::now(),
+        };
+        report.continuation = Some(resume::save(&s3b, &state).await?);
+        report.suspended = databases;
+    } else if let (Some(token), false) = (arg.resume.as_deref(), report.interrupted) {
+        resume::clear(&s3b, token).await?;
+    }
 
+    let failed_count = report.failed().count();
+    let duration = std::time::Instant::now().duration_since(start);
+    Span::current().record("duration_ms", duration.as_millis() as u64);
+    let elapsed = arrange_millis::get(duration.as_millis());
+    // databases left for the run resuming `continuation`
+    let suspended = report
+        .continuation
+        .as_ref()
+        .map(|continuation| {
+            format!(
+                ", {} database(s) left, resume with {continuation:?}",
+                report.suspended.len()
+            )
+        })
+        .unwrap_or_default();
+    match report.exit_code() {
+        report::exit_code::INTERRUPTED => {
+            warn!("INTERRUPTED: {mode:?} backup stopped by Ctrl-C{suspended}; in {elapsed}")
+        }
+        report::exit_code::SUSPENDED => {
+            info!("SUSPENDED: {mode:?} backup stopped by time budget{suspended}; in {elapsed}")
+        }
+        report::exit_code::OK => info!(
+            "OK: did complete {mode:?} backup{} in {elapsed}",
+            if report.skipped.is_empty() {
+                String::new()
+            } else {
+                format!(", {} unchanged database(s) skipped", report.skipped.len())
+            },
+        ),
+        report::exit_code::STOPPED => error!(
+            "STOPPED: {mode:?} backup of db {:?} failed, {} database(s) left in {elapsed}",
+            report.failed().next().map(|db_result| &db_result.db_name),
+            report.not_processed.len(),
+        ),
+        _ => error!(
+            "FAILED: {mode:?} backup of {failed_count} of {db_count} database(s) failed{suspended}; in {elapsed}"
+        ),
+    }
+
+    Ok(report)
+}
+
+/// Databases of CouchDB at `uri` which match `databases` (of task if not set) and `months` (`backup_only_previus` of
+/// monthly task if not set), sorted
+async fn select(
+    client: &couch_rs::Client,
+    uri: &str,
+    mode: Mode,
+    databases: Option<Vec<String>>,
+    months: Option<month::MonthRange>,
+) -> Result<Vec<String>> {
+    let regex_list = databases
+        .unwrap_or_else(|| task_settings!(mode, databases))
+        .into_iter()
+        .filter_map(|s| {
+            regex::Regex::new(&s)
+                .map_err(|err| warn!("failed Regex::new({s:?}: {err})"))
+                .ok()
+        })
+        .collect::<Vec<_>>();
+    if regex_list.is_empty() {
+        bail!("regex_list.is_empty");
+    }
     let mut db_list = client
         .list_dbs()
         .await
@@ -92,139 +496,26 @@ pub async fn run(mode: Mode) -> Result<()> {
         .into_iter()
         .filter(|s| regex_list.iter().any(|regex| regex.is_match(s)))
         .collect::<Vec<_>>();
+    let months = months.or_else(|| {
+        (mode == Mode::Monthly && settings!(task.monthly.backup_only_previus)).then(|| {
+            month::MonthRange::single(month::YearMonth::of(&chrono::Utc::now()).previous())
+        })
+    });
+    if let Some(months) = months {
+        db_list.retain(|db_name| {
+            month::YearMonth::of_db_name(db_name)
+                .map(|month| months.contains(month))
+                .unwrap_or(false)
+        });
+    }
     db_list.sort();
-    println!(
-        "selected {} database(s) for {mode:?} backup:\n{}",
+    info!(
+        databases = ?db_list,
+        "selected {} database(s) for {mode:?} backup{}",
         db_list.len(),
-        db_list
-            .iter()
-            .map(|s| format!("  - {s}"))
-            .collect::<Vec<_>>()
-            .join("\n")
-    );
-    for db_name in db_list {
-        println!("will process db {db_name:?}");
-        match client.db(&db_name).await {
-            Err(err) => {
-                eprintln!("failed to connect to db {db_name:?}: {err}");
-            }
-            Ok(db) => {
-                let batch_size = match mode {
-                    Mode::Weekly => settings!(task.weekly.chunk),
-                    Mode::Monthly => settings!(task.monthly.chunk),
-                }
-                .unwrap_or_default(); // A value of 0, means the default batch_size of 1000 is used
-                let max_results = 0; // max_results of 0 means all documents will be returned
-                let (tx, mut rx) = tokio::sync::mpsc::channel::<
-                    couch_rs::document::DocumentCollection<serde_json::value::Value>,
-                >(1);
-
-                tokio::spawn({
-                    let db_name = db_name.clone();
-
-                    let access_key = settings!(token).clone();
-                    let secret_access_key = settings!(secret).clone();
-
-                    let prefix = settings!(prefix).clone();
-                    let suffix = settings!(suffix).clone();
-                    let now = chrono::Utc::now();
-
-                    use chrono::Datelike;
-                    let day = now.day();
-                    let month = now.month();
-                    let year = now.year();
-
-                    async move {
-                        let mut chunk_id = 0;
-                        while let Some(docs) = rx.recv().await {
-                            match serde_json::to_string(&docs.rows) {
-                                Err(err) => {
-                                    eprintln!(
-                                        "failed to serde_json::to_string db {db_name:?}: {err}"
-                                    );
-                                }
-                                Ok(s) => {
-                                    let mut e = flate2::write::GzEncoder::new(
-                                        Vec::new(),
-                                        flate2::Compression::default(),
-                                    );
-                                    use std::io::Write;
-
-                                    match e.write_all(&s.into_bytes()) {
-                                        Err(err) => {
-                                            eprintln!(
-                                                "failed to e.write_all() db {db_name:?}: {err}"
-                                            );
-                                        }
-                                        Ok(()) => {
-                                            match e.finish() {
-                                                Err(err) => eprintln!(
-                                                    "failed to e.finish() db {db_name:?}: {err}"
-                                                ),
-                                                Ok(compressed_bytes) => {
-                                                    let bucket = settings!(bucket).clone();
-                                                    match s3_bucket::S3BucketBuilder::new(bucket.clone())
-                                                        .provider(s3_bucket::StaticProvider::new(
-                                                            access_key.clone(),
-                                                            secret_access_key.clone(),
-                                                            None,
-                                                            None,
-                                                        ))
-                                                        .build()
-                                                        .map_err(|err| {
-                                                            anyhow!(
-                                                                "S3BucketBuilder::new({bucket:?}): {err}"
-                                                            )
-                                                        }) {
-                                                        Err(err) => panic!("{err}"),
-                                                        Ok(s3b) => {
-                                                            let key = format!("{prefix}/{year}/{month:02}/{day:02}/{suffix}/{db_name}/{chunk_id:03}.json.gz");
-                                                            let content_length = compressed_bytes.len() as i64;
-                                                            let object_to_upload =
-                                                                s3_bucket::ObjectToUploadBuilder::from_vecu8(
-                                                                    compressed_bytes,
-                                                                )
-                                                                .content_length(Some(content_length))
-                                                                .content_type(Some("application/json".to_owned()))
-                                                                .build();
-                                                            match s3b.upload(key.clone(), object_to_upload)
-                                                                .await {
-                                                                    Err(err) => {
-                                                                        eprintln!("failed to upload {key:?} to {bucket:?}: {err}");
-                                                                    }
-                                                                    Ok(()) => {
-                                                                        println!("did upload {key:?} to {bucket:?}");
-                                                                    }
-                                                                }
-                                                        }
-                                                    }
-                                                }
-                                            }
-                                        }
-                                    }
-                                }
-                            }
-                            chunk_id += 1;
-                        }
-                    }
-                });
-
-                match db.get_all_batched(tx, batch_size, max_results).await {
-                    Err(err) => {
-                        eprintln!("failed to connect to db {db_name:?}: {err}");
-                    }
-                    Ok(count) => {
-                        println!("processed {count} docs from db {db_name:?}");
-                    }
-                }
-            }
-        }
-    }
-
-    println!(
-        "OK: did complete {mode:?} backup in {}",
-        arrange_millis::get(std::time::Instant::now().duration_since(start).as_millis()),
+        months
+            .map(|months| format!(" of month {months}"))
+            .unwrap_or_default(),
     );
-
-    Ok(())
+    Ok(db_list)
 }
@@ -0,0 +1,388 @@
+#[allow(unused_imports)]
+use anyhow::{anyhow, bail, Context, Error, Result};
+
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot};
+use tracing::field::{Field, Visit};
+use tracing_subscriber::layer::Context as LayerContext;
+use tracing_subscriber::registry::LookupSpan;
+
+/// Fields of spans and events which become labels of Loki stream (besides `job` and `host`)
+pub const LABEL_FIELDS: [&str; 2] = ["mode", "db"];
+
+/// Lines queued before they are dropped, e.g. while `loki` setting is not loaded yet or Loki is down
+const QUEUE_LEN: usize = 10_000;
+/// Lines pushed at once
+const BATCH_LEN: usize = 500;
+/// Lines are pushed at least this often
+const BATCH_INTERVAL: Duration = Duration::from_secs(1);
+/// Push of batch is retried this many times, waiting `BACKOFF`, `2 * BACKOFF`, ... between attempts
+const RETRIES: u32 = 4;
+const BACKOFF: Duration = Duration::from_millis(500);
+
+type Labels = BTreeMap<String, String>;
+
+enum Msg {
+    Line {
+        labels: Labels,
+        /// Nanoseconds since epoch
+        ts: u128,
+        line: String,
+    },
+    Flush(oneshot::Sender<()>),
+}
+
+/// `tracing` layer which sends lines to `Loki`; lines are queued until `Loki::start`
+pub struct LokiLayer {
+    tx: mpsc::Sender<Msg>,
+    enabled: Arc<AtomicBool>,
+}
+
+/// Pushes lines of its `LokiLayer` to `/loki/api/v1/push` in batches, see `start`
+pub struct Loki {
+    tx: mpsc::Sender<Msg>,
+    rx: Mutex<Option<mpsc::Receiver<Msg>>>,
+    enabled: Arc<AtomicBool>,
+    started: AtomicBool,
+}
+
+impl Loki {
+    pub fn new() -> (Self, LokiLayer) {
+        let (tx, rx) = mpsc::channel(QUEUE_LEN);
+        let enabled = Arc::new(AtomicBool::new(true));
+        (
+            Self {
+                tx: tx.clone(),
+                rx: Mutex::new(Some(rx)),
+                enabled: enabled.clone(),
+                started: AtomicBool::new(false),
+            },
+            LokiLayer { tx, enabled },
+        )
+    }
+    /// Starts pushing to `url` (“http://loki:3100/loki/api/v1/push”) with `labels` added to those of lines; lines are
+    /// dropped from now on if `url` is empty; does nothing if started already
+    pub fn start(&self, url: &str, labels: Labels) {
+        let Some(rx) = self.rx.lock().unwrap().take() else {
+            return;
+        };
+        if url.is_empty() {
+            self.enabled.store(false, Ordering::Relaxed);
+            return;
+        }
+        self.started.store(true, Ordering::Relaxed);
+        tokio::spawn(push_loop(url.to_owned(), labels, rx));
+    }
+    /// Waits until lines sent so far are pushed (or dropped after retries)
+    pub async fn flush(&self) {
+        if !self.started.load(Ordering::Relaxed) {
+            return;
+        }
+        let (ack_tx, ack_rx) = oneshot::channel();
+        if self.tx.send(Msg::Flush(ack_tx)).await.is_ok() {
+            let _ = ack_rx.await;
+        }
+    }
+}
+
+static LOKI: OnceLock<Loki> = OnceLock::new();
+
+/// Layer of global `Loki`, see `start` and `flush`
+pub fn layer() -> LokiLayer {
+    let (loki, layer) = Loki::new();
+    if LOKI.set(loki).is_err() {
+        panic!("loki::layer() is called twice");
+    }
+    layer
+}
+
+/// Starts global `Loki` (if `layer()` is installed) with labels `job` and `host`
+pub fn start(url: &str) {
+    if let Some(loki) = LOKI.get() {
+        loki.start(url, default_labels());
+    }
+}
+
+/// Flushes global `Loki`: call before exit, and before Lambda invocation returns
+pub async fn flush() {
+    if let Some(loki) = LOKI.get() {
+        loki.flush().await;
+    }
+}
+
+/// `job` is name of package, `host` is `HOSTNAME` env var or `/etc/hostname`
+pub fn default_labels() -> Labels {
+    let host = std::env::var("HOSTNAME")
+        .ok()
+        .or_else(|| std::fs::read_to_string("/etc/hostname").ok())
+        .map(|host| host.trim().to_owned())
+        .filter(|host| !host.is_empty())
+        .unwrap_or_else(|| "unknown".to_owned());
+    [
+        ("job".to_owned(), env!("CARGO_PKG_NAME").to_owned()),
+        ("host".to_owned(), host),
+    ]
+    .into_iter()
+    .collect()
+}
+
+async fn push_loop(url: String, labels: Labels, mut rx: mpsc::Receiver<Msg>) {
+    let client = reqwest::Client::new();
+    let mut batch = vec![];
+    let mut interval = tokio::time::interval(BATCH_INTERVAL);
+    loop {
+        tokio::select! {
+            msg = rx.recv() => match msg {
+                Some(Msg::Line { labels: line_labels, ts, line }) => {
+                    batch.push((line_labels, ts, line));
+                    if batch.len() >= BATCH_LEN {
+                        push(&client, &url, &labels, std::mem::take(&mut batch)).await;
+                    }
+                }
+                Some(Msg::Flush(ack)) => {
+                    push(&client, &url, &labels, std::mem::take(&mut batch)).await;
+                    let _ = ack.send(());
+                }
+                None => {
+                    push(&client, &url, &labels, batch).await;
+                    break;
+                }
+            },
+            _ = interval.tick() => {
+                push(&client, &url, &labels, std::mem::take(&mut batch)).await;
+            }
+        }
+    }
+}
+
+/// Pushes `batch` retrying on connection errors, 429 and 5xx; errors go to stderr, not to `tracing`, so they do not
+/// loop back here
+async fn push(
+    client: &reqwest::Client,
+    url: &str,
+    labels: &Labels,
+    batch: Vec<(Labels, u128, String)>,
+) {
+    if batch.is_empty() {
+        return;
+    }
+    let body = body(labels, batch);
+    let mut backoff = BACKOFF;
+    for attempt in 0..=RETRIES {
+        let err = match client.post(url).json(&body).send().await {
+            Ok(resp) if resp.status().is_success() => return,
+            Ok(resp) => {
+                let status = resp.status();
+                if !status.is_server_error() && status != reqwest::StatusCode::TOO_MANY_REQUESTS {
+                    eprintln!(
+                        "failed to push logs to {url:?}: {status} {}",
+                        resp.text().await.unwrap_or_default()
+                    );
+                    return;
+                }
+                status.to_string()
+            }
+            Err(err) => err.to_string(),
+        };
+        if attempt == RETRIES {
+            eprintln!("failed to push logs to {url:?} after {RETRIES} retries: {err}");
+        } else {
+            tokio::time::sleep(backoff).await;
+            backoff *= 2;
+        }
+    }
+}
+
+/// `{"streams": [{"stream": {labels}, "values": [["<ns>", "<line>"], ...]}, ...]}`, a stream per label set
+fn body(labels: &Labels, batch: Vec<(Labels, u128, String)>) -> serde_json::Value {
+    let mut streams = BTreeMap::<Labels, Vec<[String; 2]>>::new();
+    for (line_labels, ts, line) in batch {
+        let mut stream = labels.clone();
+        stream.extend(line_labels);
+        streams
+            .entry(stream)
+            .or_default()
+            .push([ts.to_string(), line]);
+    }
+    serde_json::json!({
+        "streams": streams
+            .into_iter()
+            .map(|(stream, values)| serde_json::json!({ "stream": stream, "values": values }))
+            .collect::<Vec<_>>()
+    })
+}
+
+/// Label fields of span, kept in its extensions
+struct SpanLabels(Labels);
+
+/// Collects `message` and the rest of fields of event as logfmt, label fields apart
+#[derive(Default)]
+struct LineVisitor {
+    message: String,
+    fields: String,
+    labels: Labels,
+}
+
+impl LineVisitor {
+    fn record(&mut self, field: &Field, value: String) {
+        if field.name() == "message" {
+            self.message = value;
+        } else if LABEL_FIELDS.contains(&field.name()) {
+            self.labels.insert(field.name().to_owned(), value);
+        } else if value.contains(char::is_whitespace) || value.contains('"') {
+            self.fields += &format!(" {}={value:?}", field.name());
+        } else {
+            self.fields += &format!(" {}={value}", field.name());
+        }
+    }
+}
+
+impl Visit for LineVisitor {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.record(field, value.to_owned());
+    }
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        self.record(field, format!("{value:?}"));
+    }
+}
+
+impl<S> tracing_subscriber::Layer<S> for LokiLayer
+where
+    S: tracing::Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(
+        &self,
+        attrs: &tracing::span::Attributes<'_>,
+        id: &tracing::span::Id,
+        ctx: LayerContext<'_, S>,
+    ) {
+        let mut visitor = LineVisitor::default();
+        attrs.record(&mut visitor);
+        if let Some(span) = ctx.span(id) {
+            span.extensions_mut().insert(SpanLabels(visitor.labels));
+        }
+    }
+    fn on_record(
+        &self,
+        id: &tracing::span::Id,
+        values: &tracing::span::Record<'_>,
+        ctx: LayerContext<'_, S>,
+    ) {
+        let mut visitor = LineVisitor::default();
+        values.record(&mut visitor);
+        if let Some(span) = ctx.span(id) {
+            if let Some(labels) = span.extensions_mut().get_mut::<SpanLabels>() {
+                labels.0.extend(visitor.labels);
+            }
+        }
+    }
+    fn on_event(&self, event: &tracing::Event<'_>, ctx: LayerContext<'_, S>) {
+        if !self.enabled.load(Ordering::Relaxed) {
+            return;
+        }
+        let mut labels = Labels::new();
+        if let Some(scope) = ctx.event_scope(event) {
+            for span in scope.from_root() {
+                if let Some(span_labels) = span.extensions().get::<SpanLabels>() {
+                    labels.extend(span_labels.0.clone());
+                }
+            }
+        }
+        let mut visitor = LineVisitor::default();
+        event.record(&mut visitor);
+        labels.extend(visitor.labels);
+        let metadata = event.metadata();
+        let line = format!(
+            "level={} target={} msg={:?}{}",
+            metadata.level().as_str().to_lowercase(),
+            metadata.target(),
+            visitor.message,
+            visitor.fields
+        );
+        let ts = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|ts| ts.as_nanos())
+            .unwrap_or_default();
+        // full queue drops the line rather than blocking the caller
+        let _ = self.tx.try_send(Msg::Line { labels, ts, line });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    /// Accepts `POST /loki/api/v1/push`, responds with `statuses` one by one (204 after them), sends bodies to `tx`
+    async fn stub(statuses: Vec<u16>, tx: mpsc::UnboundedSender<serde_json::Value>) -> String {
+        let mut statuses = statuses.into_iter();
+        let url = crate::stub::serve(move |request| {
+            assert_eq!(request.method, "POST");
+            assert_eq!(request.path, "/loki/api/v1/push");
+            tx.send(serde_json::from_slice(&request.body).unwrap())
+                .unwrap();
+            (statuses.next().unwrap_or(204), String::new())
+        })
+        .await;
+        format!("{url}/loki/api/v1/push")
+    }
+
+    #[tokio::test]
+    async fn test_push_to_stub() {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let url = stub(vec![503], tx).await;
+        let (loki, layer) = Loki::new();
+        let subscriber = tracing_subscriber::registry().with(layer);
+        tracing::subscriber::with_default(subscriber, || {
+            let run = tracing::info_span!("run", mode = "weekly");
+            let _run = run.enter();
+            let db = tracing::info_span!("db", db = "account/ab/cd/0123");
+            let _db = db.enter();
+            tracing::info!(chunk_id = 3, docs = 100, "did upload chunk");
+            drop(_db);
+            tracing::error!("backup failed");
+        });
+        let labels = [("job".to_owned(), "test".to_owned())]
+            .into_iter()
+            .collect();
+        loki.start(&url, labels);
+        loki.flush().await;
+
+        // the first push gets 503, so it is retried
+        let first = rx.recv().await.unwrap();
+        let second = rx.recv().await.unwrap();
+        assert_eq!(first, second);
+        let streams = second["streams"].as_array().unwrap();
+        assert_eq!(streams.len(), 2);
+        let db_stream = streams
+            .iter()
+            .find(|stream| stream["stream"]["db"] == "account/ab/cd/0123")
+            .unwrap();
+        assert_eq!(
+            db_stream["stream"],
+            serde_json::json!({ "job": "test", "mode": "weekly", "db": "account/ab/cd/0123" })
+        );
+        let line = db_stream["values"][0][1].as_str().unwrap();
+        assert!(line.starts_with("level=info "), "{line}");
+        assert!(
+            line.ends_with(r#"msg="did upload chunk" chunk_id=3 docs=100"#),
+            "{line}"
+        );
+        let run_stream = streams
+            .iter()
+            .find(|stream| stream["stream"].get("db").is_none())
+            .unwrap();
+        assert_eq!(
+            run_stream["stream"],
+            serde_json::json!({ "job": "test", "mode": "weekly" })
+        );
+        assert!(run_stream["values"][0][1]
+            .as_str()
+            .unwrap()
+            .starts_with("level=error "));
+        assert!(rx.try_recv().is_err());
+    }
+}
@@ -4,11 +4,11 @@ use anyhow::{anyhow, bail, Context, Error, Result};
 use tracing::{debug, error, info, span, trace, warn, Level};
 // use aws_lambda_events::event::cloudwatch_events::CloudWatchEvent;use lambda_runtime::{run, service_fn, Error, LambdaEvent};
 
-/// This is the main body for the function.
-/// Write your code inside it.
-/// There are some code example in the following URLs:
-/// - https://github.com/awslabs/aws-lambda-rust-runtime/tree/main/examples
-/// - https://github.com/aws-samples/serverless-rust-demo/
+// This is the main body for the function.
+// Write your code inside it.
+// There are some code example in the following URLs:
+// - https://github.com/awslabs/aws-lambda-rust-runtime/tree/main/examples
+// - https://github.com/aws-samples/serverless-rust-demo/
 // async fn function_handler(event: LambdaEvent<CloudWatchEvent>) -> Result<(), Error> {
 //     // Extract some useful information from the request
 //
@@ -41,8 +41,17 @@ use tracing::{debug, error, info, span, trace, warn, Level};
 use couchdb_backup::*;
 
 use clap::Parser;
+const EXIT_CODES: &str = "Exit codes:
+  0    every selected database is backed up
+  1    backup did not start or did not complete (bad config, CouchDB or S3 is not reachable, etc.)
+  2    backup of some databases failed, the rest are backed up (or left for continuation token, see 4)
+  3    backup stopped at the first failed database (--fail-fast or `on_failure: fail_fast`)
+  4    time budget (--budget) ran out with no database failed, resume with continuation token (--resume)
+  130  interrupted by Ctrl-C (stops at the next chunk or database, resume with continuation token;
+       Ctrl-C again exits at once)";
+
 #[derive(Parser, Debug)]
-#[command(author, version, about, long_about = None)]
+#[command(author, version, about, long_about = None, after_help = EXIT_CODES)]
 pub struct Args {
     /// Workdir where to read .env and config, relative to current dir
     #[arg(short, long)]
@@ -60,14 +69,92 @@ pub struct Args {
     #[arg(short, long)]
     pub no_show_opts: bool,
 
+    /// Stop at the first failed database (overrides `on_failure` of config)
+    #[arg(long)]
+    pub fail_fast: bool,
+
+    /// Write JSON report of backup to file, `-` for stdout
+    #[arg(long)]
+    pub report: Option<std::path::PathBuf>,
+
+    /// Format of log on stderr; `RUST_LOG` filters it
+    #[arg(long, value_enum, default_value_t = LogFormat::Pretty)]
+    pub log_format: LogFormat,
+
     #[command(subcommand)]
     pub cmd: Option<Command>,
 }
 
 #[derive(Debug, clap::Subcommand)]
 pub enum Command {
-    Weekly {},
-    Monthly {},
+    Weekly {
+        #[command(flatten)]
+        resumable: Resumable,
+    },
+    Monthly {
+        /// Back up databases of month YYYYMM or of range YYYYMM..YYYYMM instead of previous one
+        #[arg(short, long)]
+        month: Option<couchdb_backup::month::MonthRange>,
+
+        #[command(flatten)]
+        resumable: Resumable,
+    },
+    /// Run tasks which `cron` has elapsed within last `window` seconds, report next run of the rest
+    Scheduled {
+        #[arg(short, long, default_value_t = 3600)]
+        window: u64,
+    },
+    /// Run every task at its `cron` until Ctrl-C
+    Daemon {},
+    /// Handle Lambda event (`{"mode": "weekly", "databases": [...]}`) from JSON file, `-` for stdin, as the `lambda`
+    /// binary does; config is overridden by `COUCHDB_BACKUP__*` env vars and `config` of event
+    Event { file: std::path::PathBuf },
+    /// Restore database from S3 backup
+    Restore {
+        /// Name of database as it was backed up
+        db: String,
+
+        /// Date of backup, YYYY-MM-DD
+        #[arg(short, long)]
+        date: chrono::NaiveDate,
+
+        /// Name of database to restore into (default is the name it was backed up with)
+        #[arg(long)]
+        target_db: Option<String>,
+
+        /// Url of CouchDB to restore into (default is `database.url` of config)
+        #[arg(long)]
+        target_url: Option<String>,
+
+        /// Do not build view indexes after restore (see `views` of config)
+        #[arg(long)]
+        skip_views: bool,
+    },
+    /// Check that S3 backup decodes and matches its database (or its manifest if database is gone)
+    Verify {
+        /// Name of database as it was backed up
+        db: String,
+
+        /// Date of backup, YYYY-MM-DD
+        #[arg(short, long)]
+        date: chrono::NaiveDate,
+
+        /// Compare with manifest even if database exists
+        #[arg(long)]
+        manifest_only: bool,
+    },
+}
+
+#[derive(Debug, clap::Args)]
+pub struct Resumable {
+    /// Time budget, seconds: backup stops after the chunk being uploaded when it is spent and reports continuation
+    /// token; needs `chunk` of task
+    #[arg(long)]
+    budget: Option<u64>,
+
+    /// Continuation token of backup stopped by time budget to go on with
+    #[arg(long)]
+    resume: Option<String>,
 }
 
 use common_macros::*;
@@ -75,27 +162,139 @@ declare_env_settings! {
     config_path Option: std::path::PathBuf,
 }
 
+fn config_path(args: &Args) -> std::path::PathBuf {
+    if let Some(config) = args.config.clone() {
+        config
+    } else if let Some(config_path) = env_settings!(config_path).clone() {
+        config_path
+    } else {
+        std::path::PathBuf::from("config.yaml")
+    }
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
-    if let Some((_base_dir, args)) = get_base_dir_and_args!(args, {
-        if let Some(config) = args.config.clone() {
-            config
-        } else if let Some(config_path) = env_settings!(config_path).clone() {
-            config_path
-        } else {
-            std::path::PathBuf::from("config.yaml")
-        }
-    })? {
-        match args.cmd {
-            None => {}
-            Some(Command::Weekly {}) => {
-                couchdb_backup::run(Mode::Weekly).await?;
-            }
-            Some(Command::Monthly {}) => {
-                couchdb_backup::run(Mode::Monthly).await?;
-            }
+    if let Some((_base_dir, args)) =
+        get_base_dir_and_args!(args, config_path(&args), |args: &Args| {
+            couchdb_backup::init_tracing(args.log_format)
+        })?
+    {
+        loki::start(&settings!(loki));
+        let ret = run_command(args).await;
+        loki::flush().await;
+        let code = ret?;
+        if code != report::exit_code::OK {
+            std::process::exit(code);
         }
     }
 
     Ok(())
 }
+
+/// Exit code of command
+async fn run_command(args: Args) -> Result<i32> {
+    let config_path = config_path(&args);
+    let on_failure = args.fail_fast.then_some(report::FailurePolicy::FailFast);
+    Ok(match args.cmd {
+        None => report::exit_code::OK,
+        Some(Command::Weekly { resumable }) => {
+            let report = couchdb_backup::run(
+                Mode::Weekly,
+                RunArg {
+                    on_failure,
+                    budget: resumable.budget.map(std::time::Duration::from_secs),
+                    resume: resumable.resume,
+                    ..Default::default()
+                },
+            )
+            .await?;
+            if let Some(path) = args.report.as_deref() {
+                report::output(&report, path)?;
+            }
+            report.exit_code()
+        }
+        Some(Command::Monthly { month, resumable }) => {
+            let report = couchdb_backup::run(
+                Mode::Monthly,
+                RunArg {
+                    months: month,
+                    on_failure,
+                    budget: resumable.budget.map(std::time::Duration::from_secs),
+                    resume: resumable.resume,
+                    ..Default::default()
+                },
+            )
+            .await?;
+            if let Some(path) = args.report.as_deref() {
+                report::output(&report, path)?;
+            }
+            report.exit_code()
+        }
+        Some(Command::Scheduled { window }) => {
+            let reports = couchdb_backup::schedule::scheduled(
+                std::time::Duration::from_secs(window),
+                on_failure,
+            )
+            .await?;
+            if let Some(path) = args.report.as_deref() {
+                report::output(&reports, path)?;
+            }
+            reports
+                .iter()
+                .map(|report| report.exit_code())
+                .max()
+                .unwrap_or(report::exit_code::OK)
+        }
+        Some(Command::Event { file }) => {
+            let event = if file.as_os_str() == "-" {
+                serde_json::from_reader(std::io::stdin())
+            } else {
+                serde_json::from_reader(
+                    std::fs::File::open(&file)
+                        .map_err(|err| anyhow!("failed to open {file:?}: {err}"))?,
+                )
+            }
+            .map_err(|err| anyhow!("failed to parse {file:?}: {err}"))?;
+            let report = couchdb_backup::lambda::handle(event, &config_path, None).await?;
+            report::output(
+                &report,
+                args.report.as_deref().unwrap_or(std::path::Path::new("-")),
+            )?;
+            report.exit_code()
+        }
+        Some(Command::Daemon {}) => {
+            couchdb_backup::schedule::daemon(on_failure, args.report.as_deref()).await?;
+            report::exit_code::OK
+        }
+        Some(Command::Restore {
+            db,
+            date,
+            target_db,
+            target_url,
+            skip_views,
+        }) => {
+            couchdb_backup::restore::run(couchdb_backup::restore::RestoreArg {
+                db_name: db,
+                date,
+                target_db,
+                target_url,
+                skip_views,
+            })
+            .await?;
+            report::exit_code::OK
+        }
+        Some(Command::Verify {
+            db,
+            date,
+            manifest_only,
+        }) => {
+            couchdb_backup::verify::run(couchdb_backup::verify::VerifyArg {
+                db_name: db,
+                date,
+                manifest_only,
+            })
+            .await?;
+            report::exit_code::OK
+        }
+    })
+}
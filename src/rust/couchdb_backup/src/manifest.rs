@@ -0,0 +1,351 @@
+#[allow(unused_imports)]
+use anyhow::{anyhow, bail, Context, Error, Result};
+
+use super::*;
+
+/// `${db_prefix}/manifest.json`: describes backup of a database; uploaded last, so backup is complete if it is present
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Manifest {
+    /// Version of `couchdb_backup` which made the backup
+    pub tool_version: String,
+    pub db_name: String,
+    pub kind: Kind,
+    /// Date of backup this delta is applied on top of, or this pointer points to
+    pub previous: Option<chrono::NaiveDate>,
+    /// `update_seq` of `previous`: delta holds `_changes` since it
+    pub since: Option<String>,
+    /// How many deltas are there since full backup, this one included: 0 for full backup
+    pub chain: usize,
+    /// `update_seq` of database (`GET /{db}`) before backup started
+    pub update_seq: String,
+    /// `doc_count` of database (`GET /{db}`) before backup started
+    pub doc_count: u64,
+    pub attachments: export::Attachments,
+    /// Compression of chunks; extension of chunk key tells codec too
+    pub compression: encode::Compression,
+    /// Id of key chunks and attachments are encrypted with (see `encrypt`), not encrypted if `None`
+    pub key_id: Option<String>,
+    /// Restore creates database with them
+    pub db_meta: DbMeta,
+    /// `GET /{db}/_security`, restore sets it before loading docs
+    pub security: serde_json::Value,
+    /// Design docs, backed up apart from `chunks` (which have none) and restored after them; `None` for pointer
+    pub design: Option<Chunk>,
+    pub started_at: chrono::DateTime<chrono::Utc>,
+    pub finished_at: chrono::DateTime<chrono::Utc>,
+    /// Ordered by `id`
+    pub chunks: Vec<Chunk>,
+}
+
+/// What backup holds
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Kind {
+    /// All docs (`_all_docs`)
+    #[default]
+    Full,
+    /// Docs changed since `since` (`_changes`), deleted ones as tombstones `{_id, _rev, _deleted: true}`
+    Delta,
+    /// No chunks: database did not change since backup of `previous`, which holds its docs
+    Pointer,
+}
+
+/// Settings of database which can be set at creation only (`GET /{db}`)
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DbMeta {
+    /// Shards, `cluster.q`
+    pub q: Option<u64>,
+    /// Replicas, `cluster.n`
+    pub n: Option<u64>,
+    /// `props.partitioned`
+    #[serde(default)]
+    pub partitioned: bool,
+}
+
+impl DbMeta {
+    /// Of response of `GET /{db}`
+    pub fn of_db_info(db_info: &serde_json::Value) -> Self {
+        Self {
+            q: db_info.pointer("/cluster/q").and_then(|q| q.as_u64()),
+            n: db_info.pointer("/cluster/n").and_then(|n| n.as_u64()),
+            partitioned: db_info
+                .pointer("/props/partitioned")
+                .and_then(|partitioned| partitioned.as_bool())
+                .unwrap_or(false),
+        }
+    }
+}
+
+/// `${bucket_prefix}/${prefix}/checkpoint/${suffix}/${db_name}.json`: points to the last complete backup of database,
+/// so the next incremental backup knows what to start from
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Checkpoint {
+    pub date: chrono::NaiveDate,
+    pub kind: Kind,
+    pub update_seq: Option<String>,
+}
+
+/// What delta is made on top of
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Base {
+    pub date: chrono::NaiveDate,
+    pub update_seq: String,
+    /// `chain` of base backup
+    pub chain: usize,
+}
+
+/// Chunk of backup as uploaded
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Chunk {
+    pub id: usize,
+    /// Relative to `${db_prefix}`
+    pub key: String,
+    pub docs: usize,
+    /// Size of JSON
+    pub bytes_raw: usize,
+    /// Size of uploaded object
+    pub bytes_compressed: usize,
+    /// Hex encoded SHA-256 of uploaded object
+    pub sha256: String,
+}
+
+/// Hex encoded SHA-256 of `bytes`
+pub fn sha256(bytes: &[u8]) -> String {
+    use sha2::Digest;
+    format!("{:x}", sha2::Sha256::digest(bytes))
+}
+
+pub fn key(db_prefix: &str) -> String {
+    format!("{db_prefix}/manifest.json")
+}
+
+pub async fn upload(s3b: &s3_bucket::S3Bucket, db_prefix: &str, manifest: &Manifest) -> Result<()> {
+    let key = key(db_prefix);
+    let body = serde_json::to_vec_pretty(manifest)?;
+    let content_length = body.len() as i64;
+    let object_to_upload = s3_bucket::ObjectToUploadBuilder::from_vecu8(body)
+        .content_length(Some(content_length))
+        .content_type(Some("application/json".to_owned()))
+        .build();
+    s3b.upload(key.clone(), object_to_upload)
+        .await
+        .map_err(|err| anyhow!("failed to upload {key:?}: {err}"))
+}
+
+/// Deletes manifest at `db_prefix` if any, so backup being (re)written there is not taken as complete
+pub async fn delete(s3b: &s3_bucket::S3Bucket, db_prefix: &str) -> Result<()> {
+    let key = key(db_prefix);
+    if s3b.head(key.clone()).await?.is_some() {
+        s3b.delete(key.clone())
+            .await
+            .map_err(|err| anyhow!("failed to delete {key:?}: {err}"))?;
+    }
+    Ok(())
+}
+
+pub async fn upload_checkpoint(
+    s3b: &s3_bucket::S3Bucket,
+    db_name: &str,
+    checkpoint: &Checkpoint,
+) -> Result<()> {
+    let key = bucket::checkpoint_key(db_name)?;
+    let body = serde_json::to_vec_pretty(checkpoint)?;
+    let content_length = body.len() as i64;
+    let object_to_upload = s3_bucket::ObjectToUploadBuilder::from_vecu8(body)
+        .content_length(Some(content_length))
+        .content_type(Some("application/json".to_owned()))
+        .build();
+    s3b.upload(key.clone(), object_to_upload)
+        .await
+        .map_err(|err| anyhow!("failed to upload {key:?}: {err}"))
+}
+
+/// `None` if there is no checkpoint of `db_name`
+pub async fn download_checkpoint(
+    s3b: &s3_bucket::S3Bucket,
+    db_name: &str,
+) -> Result<Option<Checkpoint>> {
+    let key = bucket::checkpoint_key(db_name)?;
+    if s3b.head(key.clone()).await?.is_none() {
+        return Ok(None);
+    }
+    let body = bucket::download(s3b, &key).await?;
+    serde_json::from_slice(&body)
+        .map(Some)
+        .map_err(|err| anyhow!("failed to parse {key:?}: {err}"))
+}
+
+/// Base of delta backup of `db_name` made on `today`: the last complete backup pointed by checkpoint (its base if
+/// it is a delta of `today` being redone); `None` if full backup is due: no checkpoint, no complete manifest, full
+/// backup of `today` being redone or `max_deltas` reached
+pub async fn base(
+    s3b: &s3_bucket::S3Bucket,
+    db_name: &str,
+    today: chrono::NaiveDate,
+    max_deltas: usize,
+) -> Result<Option<Base>> {
+    let Some((date, manifest)) = last(s3b, db_name).await? else {
+        info!("no complete backup of db {db_name:?} to start from, will do full backup");
+        return Ok(None);
+    };
+    Ok(base_of(&manifest, date, today, max_deltas))
+}
+
+/// Date and complete manifest of the last backup of `db_name` pointed by checkpoint; `None` if there is no checkpoint
+/// or backup it points to has no complete manifest
+pub async fn last(
+    s3b: &s3_bucket::S3Bucket,
+    db_name: &str,
+) -> Result<Option<(chrono::NaiveDate, Manifest)>> {
+    let Some(checkpoint) = download_checkpoint(s3b, db_name).await? else {
+        return Ok(None);
+    };
+    Ok(
+        download(s3b, &bucket::db_prefix(&checkpoint.date, db_name)?)
+            .await?
+            .map(|manifest| (checkpoint.date, manifest)),
+    )
+}
+
+fn base_of(
+    manifest: &Manifest,
+    date: chrono::NaiveDate,
+    today: chrono::NaiveDate,
+    max_deltas: usize,
+) -> Option<Base> {
+    let base = if date < today {
+        Base {
+            date,
+            update_seq: manifest.update_seq.clone(),
+            chain: manifest.chain,
+        }
+    } else {
+        match (manifest.kind, manifest.previous, manifest.since.clone()) {
+            (Kind::Delta, Some(date), Some(update_seq)) => Base {
+                date,
+                update_seq,
+                chain: manifest.chain.saturating_sub(1),
+            },
+            _ => return None,
+        }
+    };
+    (base.chain < max_deltas).then_some(base)
+}
+
+/// `None` if there is no manifest at `db_prefix`
+pub async fn download(s3b: &s3_bucket::S3Bucket, db_prefix: &str) -> Result<Option<Manifest>> {
+    let key = key(db_prefix);
+    if s3b.head(key.clone()).await?.is_none() {
+        return Ok(None);
+    }
+    let body = bucket::download(s3b, &key).await?;
+    serde_json::from_slice(&body)
+        .map(Some)
+        .map_err(|err| anyhow!("failed to parse {key:?}: {err}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sha256() {
+        assert_eq!(
+            sha256(b"abc"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+
+    fn manifest_json() -> serde_json::Value {
+        serde_json::json!({
+            "tool_version": "0.13.0",
+            "db_name": "a",
+            "kind": "full",
+            "previous": null,
+            "since": null,
+            "chain": 0,
+            "update_seq": "10-a",
+            "doc_count": 0,
+            "attachments": "separate",
+            "compression": { "codec": "gzip" },
+            "key_id": null,
+            "db_meta": { "q": 2, "n": 1 },
+            "security": {},
+            "design": null,
+            "started_at": "2024-03-02T02:00:00Z",
+            "finished_at": "2024-03-02T02:00:01Z",
+            "chunks": [],
+        })
+    }
+
+    #[test]
+    fn test_manifest() {
+        let manifest: Manifest = serde_json::from_value(manifest_json()).unwrap();
+        assert!(manifest.chunks.is_empty());
+        assert_eq!(manifest.attachments, export::Attachments::Separate);
+        assert_eq!(manifest.kind, Kind::Full);
+        for field in ["tool_version", "kind", "update_seq", "doc_count", "chunks"] {
+            let mut json = manifest_json();
+            json.as_object_mut().unwrap().remove(field);
+            assert!(serde_json::from_value::<Manifest>(json).is_err(), "{field}");
+        }
+    }
+
+    #[test]
+    fn test_db_meta() {
+        let db_info = serde_json::json!({
+            "db_name": "account/ab/cd/0123",
+            "cluster": { "q": 8, "n": 3, "w": 2, "r": 2 },
+            "props": { "partitioned": true },
+        });
+        assert_eq!(
+            DbMeta::of_db_info(&db_info),
+            DbMeta {
+                q: Some(8),
+                n: Some(3),
+                partitioned: true,
+            }
+        );
+        assert_eq!(
+            DbMeta::of_db_info(&serde_json::json!({ "props": {} })),
+            DbMeta::default()
+        );
+    }
+
+    #[test]
+    fn test_base_of() {
+        let date = |day| chrono::NaiveDate::from_ymd_opt(2024, 3, day).unwrap();
+        let mut manifest: Manifest = serde_json::from_value(manifest_json()).unwrap();
+        let base = Base {
+            date: date(2),
+            update_seq: "10-a".to_owned(),
+            chain: 0,
+        };
+        assert_eq!(base_of(&manifest, date(2), date(9), 6), Some(base.clone()));
+        // full backup of today is redone
+        assert_eq!(base_of(&manifest, date(9), date(9), 6), None);
+
+        manifest.kind = Kind::Delta;
+        manifest.previous = Some(date(2));
+        manifest.since = Some("7-a".to_owned());
+        manifest.chain = 1;
+        assert_eq!(
+            base_of(&manifest, date(9), date(16), 6),
+            Some(Base {
+                date: date(9),
+                chain: 1,
+                ..base.clone()
+            })
+        );
+        // delta of today is redone on top of its base
+        assert_eq!(
+            base_of(&manifest, date(9), date(9), 6),
+            Some(Base {
+                update_seq: "7-a".to_owned(),
+                ..base
+            })
+        );
+        manifest.chain = 6;
+        assert_eq!(base_of(&manifest, date(9), date(16), 6), None);
+    }
+}
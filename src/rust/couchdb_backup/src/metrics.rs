@@ -0,0 +1,300 @@
+#[allow(unused_imports)]
+use anyhow::{anyhow, bail, Context, Error, Result};
+
+use super::*;
+use prometheus::{Encoder, GaugeVec, IntGaugeVec, Opts, Registry, TextEncoder};
+use std::sync::OnceLock;
+use std::time::Duration;
+
+/// Prefix of metric names
+const NAMESPACE: &str = "couchdb_backup";
+/// Label of every metric
+const MODE: &str = "mode";
+/// Timeout of push to Pushgateway
+const PUSH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Metrics of `run()`, each labeled `mode`: gauges of the last run rather than counters, as one-shot and Lambda
+/// processes push them once and start from zero the next run (so `increase()` and `rate()` do not apply to them)
+pub struct Metrics {
+    registry: Registry,
+    databases_selected: IntGaugeVec,
+    databases_backed_up: IntGaugeVec,
+    databases_skipped: IntGaugeVec,
+    databases_failed: IntGaugeVec,
+    docs_exported: IntGaugeVec,
+    bytes_exported: IntGaugeVec,
+    bytes_uploaded: IntGaugeVec,
+    upload_retries: IntGaugeVec,
+    last_success: IntGaugeVec,
+    run_duration: GaugeVec,
+}
+
+impl Metrics {
+    pub fn new() -> Result<Self> {
+        let registry = Registry::new();
+        let opts = |name: &str, help: &str| Opts::new(name, help).namespace(NAMESPACE);
+        macro_rules! register {
+            ($vec:ident, $name:literal, $help:literal) => {{
+                let metric = prometheus::$vec::new(opts($name, $help), &[MODE])?;
+                registry.register(Box::new(metric.clone()))?;
+                metric
+            }};
+        }
+        Ok(Self {
+            databases_selected: register!(
+                IntGaugeVec,
+                "databases_selected",
+                "Databases selected by the last run"
+            ),
+            databases_backed_up: register!(
+                IntGaugeVec,
+                "databases_backed_up",
+                "Databases backed up by the last run"
+            ),
+            databases_skipped: register!(
+                IntGaugeVec,
+                "databases_skipped",
+                "Databases skipped by the last run as unchanged since the previous backup"
+            ),
+            databases_failed: register!(
+                IntGaugeVec,
+                "databases_failed",
+                "Databases which backup failed in the last run"
+            ),
+            docs_exported: register!(
+                IntGaugeVec,
+                "docs_exported",
+                "Docs exported by the last run"
+            ),
+            bytes_exported: register!(
+                IntGaugeVec,
+                "bytes_exported",
+                "Bytes of docs exported by the last run, before compression"
+            ),
+            bytes_uploaded: register!(
+                IntGaugeVec,
+                "bytes_uploaded",
+                "Bytes of chunks uploaded by the last run, compressed and encrypted"
+            ),
+            upload_retries: register!(
+                IntGaugeVec,
+                "upload_retries",
+                "Retries of uploads of objects and parts of chunks in the last run"
+            ),
+            last_success: register!(
+                IntGaugeVec,
+                "last_success_timestamp_seconds",
+                "When the last run which backed up every selected database finished"
+            ),
+            run_duration: register!(GaugeVec, "run_duration_seconds", "Duration of the last run"),
+            registry,
+        })
+    }
+
+    /// Records run of `mode` which took `duration` with `upload_retries`; `report` is `None` if it failed to start
+    pub fn record(
+        &self,
+        mode: Mode,
+        report: Option<&report::BackupReport>,
+        duration: Duration,
+        upload_retries: u64,
+    ) {
+        let mode = mode.to_string();
+        let labels = [mode.as_str()];
+        self.run_duration
+            .with_label_values(&labels)
+            .set(duration.as_secs_f64());
+        self.upload_retries
+            .with_label_values(&labels)
+            .set(upload_retries as i64);
+        let Some(report) = report else {
+            return;
+        };
+        self.databases_selected
+            .with_label_values(&labels)
+            .set(report.selected() as i64);
+        let failed = report.failed().count();
+        self.databases_backed_up
+            .with_label_values(&labels)
+            .set((report.databases.len() - failed) as i64);
+        self.databases_failed
+            .with_label_values(&labels)
+            .set(failed as i64);
+        self.databases_skipped
+            .with_label_values(&labels)
+            .set(report.skipped.len() as i64);
+        let sum = |field: fn(&report::DbResult) -> u64| {
+            report.databases.iter().map(field).sum::<u64>() as i64
+        };
+        self.docs_exported
+            .with_label_values(&labels)
+            .set(sum(|db_result| db_result.docs));
+        self.bytes_exported
+            .with_label_values(&labels)
+            .set(sum(|db_result| db_result.bytes_raw));
+        self.bytes_uploaded
+            .with_label_values(&labels)
+            .set(sum(|db_result| db_result.bytes_compressed));
+        if report.exit_code() == report::exit_code::OK {
+            self.last_success
+                .with_label_values(&labels)
+                .set(chrono::Utc::now().timestamp());
+        }
+    }
+
+    /// Text exposition of metrics, of `mode` only if set
+    pub fn encode(&self, mode: Option<Mode>) -> String {
+        let mut families = self.registry.gather();
+        if let Some(mode) = mode {
+            let mode = mode.to_string();
+            for family in families.iter_mut() {
+                let metrics = family
+                    .take_metric()
+                    .into_iter()
+                    .filter(|metric| {
+                        metric
+                            .get_label()
+                            .iter()
+                            .any(|label| label.get_name() == MODE && label.get_value() == mode)
+                    })
+                    .collect();
+                family.set_metric(metrics);
+            }
+            families.retain(|family| !family.get_metric().is_empty());
+        }
+        let mut buf = vec![];
+        // writing to `Vec` fails only on invalid metric, which `register!` would have refused
+        let _ = TextEncoder::new().encode(&families, &mut buf);
+        String::from_utf8_lossy(&buf).into_owned()
+    }
+}
+
+static METRICS: OnceLock<Metrics> = OnceLock::new();
+
+/// Global `Metrics`
+pub fn metrics() -> &'static Metrics {
+    METRICS.get_or_init(|| Metrics::new().expect("metrics are registered once"))
+}
+
+/// Records run to global `Metrics`, see `Metrics::record`; then pushes them if `metrics.pushgateway` is set
+pub async fn record(
+    mode: Mode,
+    report: Option<&report::BackupReport>,
+    duration: Duration,
+    upload_retries: u64,
+) {
+    metrics().record(mode, report, duration, upload_retries);
+    let pushgateway = settings!(metrics)
+        .as_ref()
+        .and_then(|metrics| metrics.pushgateway.clone());
+    if let Some(url) = pushgateway {
+        if let Err(err) = push(&url, mode).await {
+            warn!("{err}");
+        }
+    }
+}
+
+/// Pushes metrics of `mode` to group `job=couchdb_backup,mode=${mode}` of Pushgateway at `url`
+/// (“http://pushgateway:9091”); POST replaces only metrics pushed, so `last_success_timestamp_seconds` of failed run
+/// keeps the previous value
+pub async fn push(url: &str, mode: Mode) -> Result<()> {
+    let url = format!(
+        "{}/metrics/job/{}/{MODE}/{mode}",
+        url.trim_end_matches('/'),
+        env!("CARGO_PKG_NAME")
+    );
+    reqwest::Client::new()
+        .post(&url)
+        .timeout(PUSH_TIMEOUT)
+        .header(reqwest::header::CONTENT_TYPE, prometheus::TEXT_FORMAT)
+        .body(metrics().encode(Some(mode)))
+        .send()
+        .await
+        .and_then(|resp| resp.error_for_status())
+        .map(|_| ())
+        .map_err(|err| anyhow!("failed to push metrics to {url:?}: {err}"))
+}
+
+/// Serves global `Metrics` on `http://${listen}/metrics` (“[::]:9185”) in background
+pub fn serve(listen: &str) -> Result<()> {
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Body, Method, Request, Response, StatusCode};
+    let addr = listen
+        .parse::<std::net::SocketAddr>()
+        .map_err(|err| anyhow!("failed to parse metrics.listen {listen:?}: {err}"))?;
+    let server = hyper::Server::try_bind(&addr)
+        .map_err(|err| anyhow!("failed to listen on {addr} for metrics: {err}"))?
+        .serve(make_service_fn(|_| async {
+            Ok::<_, std::convert::Infallible>(service_fn(|req: Request<Body>| async move {
+                let resp = match (req.method(), req.uri().path()) {
+                    (&Method::GET, "/metrics") => Response::builder()
+                        .header(hyper::header::CONTENT_TYPE, prometheus::TEXT_FORMAT)
+                        .body(Body::from(metrics().encode(None))),
+                    _ => Response::builder()
+                        .status(StatusCode::NOT_FOUND)
+                        .body(Body::empty()),
+                };
+                resp.map_err(|err| anyhow!("{err}"))
+            }))
+        }));
+    info!("will serve metrics on http://{addr}/metrics");
+    tokio::spawn(async move {
+        if let Err(err) = server.await {
+            error!("metrics server on {addr} failed: {err}");
+        }
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_record() {
+        let metrics = Metrics::new().unwrap();
+        let mut report = report::BackupReport::new(Mode::Monthly, report::FailurePolicy::Continue);
+        let mut db_result = report::DbResult::new("account/ab/cd/0123-202402");
+        db_result.docs = 7;
+        db_result.bytes_raw = 700;
+        db_result.bytes_compressed = 100;
+        report.databases.push(db_result);
+        report.skipped.push("account/ab/cd/4567-202402".to_owned());
+        metrics.record(Mode::Monthly, Some(&report), Duration::from_secs(90), 2);
+
+        let mut failed = report::DbResult::new("account/ab/cd/0123");
+        failed.fail(None, report::ErrorClass::Export, "no db");
+        let mut report = report::BackupReport::new(Mode::Weekly, report::FailurePolicy::Continue);
+        report.databases.push(failed);
+        metrics.record(Mode::Weekly, Some(&report), Duration::from_secs(1), 0);
+
+        let text = metrics.encode(Some(Mode::Monthly));
+        for line in [
+            "couchdb_backup_databases_selected{mode=\"monthly\"} 2",
+            "couchdb_backup_databases_backed_up{mode=\"monthly\"} 1",
+            "couchdb_backup_databases_skipped{mode=\"monthly\"} 1",
+            "couchdb_backup_docs_exported{mode=\"monthly\"} 7",
+            "couchdb_backup_bytes_exported{mode=\"monthly\"} 700",
+            "couchdb_backup_bytes_uploaded{mode=\"monthly\"} 100",
+            "couchdb_backup_upload_retries{mode=\"monthly\"} 2",
+            "couchdb_backup_run_duration_seconds{mode=\"monthly\"} 90",
+        ] {
+            assert!(
+                text.lines().any(|s| s == line),
+                "{line:?} is not in:\n{text}"
+            );
+        }
+        assert!(text.contains("couchdb_backup_last_success_timestamp_seconds{mode=\"monthly\"}"));
+        assert!(!text.contains("weekly"));
+
+        let text = metrics.encode(None);
+        assert!(text.contains("couchdb_backup_databases_failed{mode=\"weekly\"} 1"));
+        assert!(!text.contains("couchdb_backup_last_success_timestamp_seconds{mode=\"weekly\"}"));
+
+        // the next run replaces values of the last one
+        let report = report::BackupReport::new(Mode::Monthly, report::FailurePolicy::Continue);
+        metrics.record(Mode::Monthly, Some(&report), Duration::from_secs(1), 0);
+        let text = metrics.encode(Some(Mode::Monthly));
+        assert!(text.contains("couchdb_backup_docs_exported{mode=\"monthly\"} 0"));
+    }
+}
@@ -0,0 +1,139 @@
+#[allow(unused_imports)]
+use anyhow::{anyhow, bail, Context, Error, Result};
+
+/// Month of `-YYYYMM` suffix of monthly database name
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct YearMonth {
+    pub year: i32,
+    pub month: u32,
+}
+
+impl YearMonth {
+    pub fn of(date: &impl chrono::Datelike) -> Self {
+        Self {
+            year: date.year(),
+            month: date.month(),
+        }
+    }
+
+    /// Like `date '+%Y%m' --date='-1 month'`
+    pub fn previous(self) -> Self {
+        if self.month == 1 {
+            Self {
+                year: self.year - 1,
+                month: 12,
+            }
+        } else {
+            Self {
+                year: self.year,
+                month: self.month - 1,
+            }
+        }
+    }
+
+    /// Month of `-YYYYMM` suffix of `db_name`, if any
+    pub fn of_db_name(db_name: &str) -> Option<Self> {
+        db_name
+            .rsplit_once('-')
+            .filter(|(_, suffix)| suffix.len() == 6)
+            .and_then(|(_, suffix)| suffix.parse().ok())
+    }
+}
+
+impl std::str::FromStr for YearMonth {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.len() != 6 || !s.chars().all(|ch| ch.is_ascii_digit()) {
+            bail!("{s:?}: expected YYYYMM");
+        }
+        let year = s[..4].parse()?;
+        let month = s[4..].parse()?;
+        if !(1..=12).contains(&month) {
+            bail!("{s:?}: invalid month {month}");
+        }
+        Ok(Self { year, month })
+    }
+}
+
+impl std::fmt::Display for YearMonth {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{:04}{:02}", self.year, self.month)
+    }
+}
+
+/// Inclusive range of months: `YYYYMM` or `YYYYMM..YYYYMM`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MonthRange {
+    pub from: YearMonth,
+    pub to: YearMonth,
+}
+
+impl MonthRange {
+    pub fn single(month: YearMonth) -> Self {
+        Self {
+            from: month,
+            to: month,
+        }
+    }
+    pub fn contains(&self, month: YearMonth) -> bool {
+        (self.from..=self.to).contains(&month)
+    }
+}
+
+impl std::str::FromStr for MonthRange {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once("..") {
+            None => Ok(Self::single(s.parse()?)),
+            Some((from, to)) => {
+                let (from, to) = (from.parse()?, to.parse()?);
+                if from > to {
+                    bail!("{s:?}: {from} is after {to}");
+                }
+                Ok(Self { from, to })
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for MonthRange {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        if self.from == self.to {
+            write!(f, "{}", self.from)
+        } else {
+            write!(f, "{}..{}", self.from, self.to)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_of_db_name() {
+        assert_eq!(
+            YearMonth::of_db_name("account/ab/cd/0123456789abcdef-202311"),
+            Some(YearMonth {
+                year: 2023,
+                month: 11
+            })
+        );
+        assert_eq!(
+            YearMonth::of_db_name("account/ab/cd/0123456789abcdef"),
+            None
+        );
+        assert_eq!(YearMonth::of_db_name("account/ab/cd/0123-202313"), None);
+        assert_eq!(YearMonth::of_db_name("account/ab/cd/0123-2023110"), None);
+    }
+
+    #[test]
+    fn test_previous_and_range() {
+        let january = "202401".parse::<YearMonth>().unwrap();
+        assert_eq!(january.previous().to_string(), "202312");
+        let range = "202310..202401".parse::<MonthRange>().unwrap();
+        assert!(range.contains(january.previous()));
+        assert!(!range.contains("202309".parse().unwrap()));
+        assert!("202401..202310".parse::<MonthRange>().is_err());
+    }
+}
@@ -0,0 +1,539 @@
+#[allow(unused_imports)]
+use anyhow::{anyhow, bail, Context, Error, Result};
+
+use super::*;
+use encode::ChunkEncoder;
+use export::Fetched;
+use report::{DbResult, ErrorClass};
+use s3_bucket::MultipartWriter;
+use tokio::sync::mpsc;
+use tracing::field::Empty;
+
+/// Span `db` to run `backup_db` in; `docs`, `bytes_raw`, `bytes_compressed` and `duration_ms` are recorded once it is
+/// backed up
+pub fn db_span(db_name: &str) -> Span {
+    tracing::info_span!(
+        "db",
+        db = db_name,
+        docs = Empty,
+        bytes_raw = Empty,
+        bytes_compressed = Empty,
+        duration_ms = Empty,
+    )
+}
+
+/// Backs up database `db_name`: fetch stage and encode-upload stage (streams docs through compressor into multipart
+/// upload) run concurrently connected by channel; manifest is uploaded after all chunks are uploaded successfully
+/// (and manifest of previous backup of the day is deleted before the first one), then checkpoint pointing to it.
+/// If task is `incremental`, only docs changed since base backup (see `manifest::base`) are backed up.
+/// If it is past `deadline` (or Ctrl-C is pressed) after a chunk is uploaded, backup stops with `DbResult::progress`
+/// set; backup goes on from `progress` if it is set. `deadline` is not checked within chunk, see `RunArg::budget`
+#[allow(clippy::too_many_arguments)]
+pub async fn backup_db(
+    client: &couch_rs::Client,
+    s3b: &s3_bucket::S3Bucket,
+    key: Option<&encrypt::Key>,
+    mode: Mode,
+    db_name: &str,
+    deadline: Option<std::time::Instant>,
+    progress: Option<resume::DbProgress>,
+) -> DbResult {
+    let start = std::time::Instant::now();
+    let mut ret = DbResult::new(db_name);
+    let chunk = task_settings!(mode, chunk);
+    let attachments = task_settings!(mode, attachments);
+    let compression = task_settings!(mode, compression);
+    let db_path = export::url_encode(db_name);
+
+    let progress = match progress {
+        Some(progress) => {
+            info!(
+                "will go on with backup of db {db_name:?} from chunk {:03}",
+                progress.chunks.len()
+            );
+            progress
+        }
+        None => match start_db(client, s3b, key, mode, db_name, &mut ret).await {
+            Some(progress) => progress,
+            None => return ret,
+        },
+    };
+    let resume::DbProgress {
+        started_at,
+        update_seq,
+        doc_count,
+        db_meta,
+        security,
+        design,
+        base,
+        chunks: mut uploaded_chunks,
+        position,
+        ..
+    } = progress;
+    let today = started_at.date_naive();
+    let db_prefix = match bucket::db_prefix(&today, db_name) {
+        Ok(db_prefix) => db_prefix,
+        Err(err) => {
+            ret.fail(None, ErrorClass::Upload, err);
+            return ret;
+        }
+    };
+    for chunk in uploaded_chunks.iter() {
+        ret.add(chunk);
+    }
+
+    let (docs_tx, docs_rx) = mpsc::channel::<Fetched>(DOCS_IN_FLIGHT);
+    let uploader = tokio::spawn(
+        upload(
+            s3b.clone(),
+            db_prefix.clone(),
+            uploaded_chunks.len(),
+            compression,
+            key.cloned(),
+            docs_rx,
+        )
+        .instrument(Span::current()),
+    );
+    let fetched = match base.as_ref() {
+        None => export::fetch_chunks(
+            client,
+            s3b,
+            &db_path,
+            &db_prefix,
+            match position {
+                Some(resume::Position::LastId(last_id)) => Some(last_id),
+                _ => None,
+            },
+            chunk,
+            attachments,
+            key,
+            deadline,
+            docs_tx,
+        )
+        .await
+        .map(|last_id| last_id.map(resume::Position::LastId)),
+        Some(base) => export::fetch_changes(
+            client,
+            s3b,
+            &db_path,
+            &db_prefix,
+            match &position {
+                Some(resume::Position::LastSeq(last_seq)) => last_seq,
+                _ => &base.update_seq,
+            },
+            chunk,
+            attachments,
+            key,
+            deadline,
+            docs_tx,
+        )
+        .await
+        .map(|last_seq| last_seq.map(resume::Position::LastSeq)),
+    };
+    let uploaded = uploader.await;
+    let mut chunks = vec![];
+
+    let stopped_at = match fetched {
+        Ok(stopped_at) => stopped_at,
+        Err(err) => {
+            ret.fail(None, ErrorClass::Export, err);
+            None
+        }
+    };
+    match uploaded {
+        Err(err) => ret.fail(
+            None,
+            ErrorClass::Internal,
+            format!("upload stage failed: {err}"),
+        ),
+        Ok(outcomes) => {
+            for (chunk_id, outcome) in outcomes {
+                match outcome {
+                    Ok(chunk) => {
+                        ret.add(&chunk);
+                        chunks.push(chunk);
+                    }
+                    Err((class, err)) => ret.fail(Some(chunk_id), class, err),
+                }
+            }
+        }
+    }
+    chunks.sort_by_key(|chunk| chunk.id);
+    uploaded_chunks.extend(chunks);
+    let chunks = uploaded_chunks;
+
+    if let (true, Some(position)) = (ret.is_ok(), stopped_at) {
+        info!(
+            "did stop backup of db {db_name:?} {} after {} chunk(s), in {}",
+            if delay::is_interrupted() {
+                "by Ctrl-C"
+            } else {
+                "at deadline"
+            },
+            chunks.len(),
+            arrange_millis::get(std::time::Instant::now().duration_since(start).as_millis()),
+        );
+        ret.progress = Some(resume::DbProgress {
+            db_name: db_name.to_owned(),
+            started_at,
+            update_seq,
+            doc_count,
+            db_meta,
+            security,
+            design,
+            base,
+            chunks,
+            position: Some(position),
+        });
+        return ret;
+    }
+    if ret.is_ok() {
+        let kind = if base.is_some() {
+            manifest::Kind::Delta
+        } else {
+            manifest::Kind::Full
+        };
+        let manifest = manifest::Manifest {
+            tool_version: env!("CARGO_PKG_VERSION").to_owned(),
+            db_name: db_name.to_owned(),
+            kind,
+            previous: base.as_ref().map(|base| base.date),
+            since: base.as_ref().map(|base| base.update_seq.clone()),
+            chain: base.as_ref().map(|base| base.chain + 1).unwrap_or(0),
+            update_seq,
+            doc_count,
+            attachments,
+            compression,
+            key_id: key.map(|key| key.id().to_owned()),
+            db_meta,
+            security,
+            design: Some(design),
+            started_at,
+            finished_at: chrono::Utc::now(),
+            chunks,
+        };
+        if let Err(err) = manifest::upload(s3b, &db_prefix, &manifest).await {
+            ret.fail(None, ErrorClass::Manifest, err);
+        } else if let Err(err) = manifest::upload_checkpoint(
+            s3b,
+            db_name,
+            &manifest::Checkpoint {
+                date: today,
+                kind,
+                update_seq: Some(manifest.update_seq.clone()),
+            },
+        )
+        .await
+        {
+            ret.fail(None, ErrorClass::Manifest, err);
+        }
+    }
+    if ret.is_ok() {
+        let duration = std::time::Instant::now().duration_since(start);
+        Span::current()
+            .record("docs", ret.docs)
+            .record("bytes_raw", ret.bytes_raw)
+            .record("bytes_compressed", ret.bytes_compressed)
+            .record("duration_ms", duration.as_millis() as u64);
+        info!(
+            "did backup db {db_name:?}{}: {} doc(s) in {} chunk(s), {} -> {} bytes, in {}",
+            base.map(|base| format!(" (delta on top of backup of {})", base.date))
+                .unwrap_or_default(),
+            ret.docs,
+            ret.chunks,
+            ret.bytes_raw,
+            ret.bytes_compressed,
+            arrange_millis::get(duration.as_millis()),
+        );
+    }
+    ret
+}
+
+/// Everything `backup_db` does before the first chunk: info of database, unchanged check, base of delta, design docs;
+/// `None` if there is nothing to back up (`ret` is skipped) or it failed (`ret` has failure)
+async fn start_db(
+    client: &couch_rs::Client,
+    s3b: &s3_bucket::S3Bucket,
+    key: Option<&encrypt::Key>,
+    mode: Mode,
+    db_name: &str,
+    ret: &mut DbResult,
+) -> Option<resume::DbProgress> {
+    let started_at = chrono::Utc::now();
+    let compression = task_settings!(mode, compression);
+    let today = started_at.date_naive();
+    let db_prefix = match bucket::db_prefix(&today, db_name) {
+        Ok(db_prefix) => db_prefix,
+        Err(err) => {
+            ret.fail(None, ErrorClass::Manifest, err);
+            return None;
+        }
+    };
+
+    let db_info = match client.get_info(db_name).await {
+        Ok(db_info) => db_info,
+        Err(err) => {
+            ret.fail(
+                None,
+                ErrorClass::Export,
+                format!("failed to get info of db {db_name:?}: {err}"),
+            );
+            return None;
+        }
+    };
+    let unchanged = task_settings!(mode, unchanged);
+    if unchanged != Unchanged::Backup {
+        match manifest::last(s3b, db_name).await {
+            Err(err) => {
+                ret.fail(None, ErrorClass::Manifest, err);
+                return None;
+            }
+            Ok(Some((date, last)))
+                if last.update_seq == db_info.update_seq && last.doc_count == db_info.doc_count =>
+            {
+                // backup of today is there already if `date` is today
+                if unchanged == Unchanged::Pointer && date < today {
+                    let pointer = manifest::Manifest {
+                        tool_version: env!("CARGO_PKG_VERSION").to_owned(),
+                        kind: manifest::Kind::Pointer,
+                        previous: Some(date),
+                        since: None,
+                        design: None,
+                        started_at,
+                        finished_at: chrono::Utc::now(),
+                        chunks: vec![],
+                        ..last
+                    };
+                    if let Err(err) = manifest::upload(s3b, &db_prefix, &pointer).await {
+                        ret.fail(None, ErrorClass::Manifest, err);
+                        return None;
+                    }
+                    info!("db {db_name:?} did not change since backup of {date}, did record pointer to it");
+                } else {
+                    info!("db {db_name:?} did not change since backup of {date}, skipped");
+                }
+                ret.skipped = true;
+                return None;
+            }
+            Ok(_) => {}
+        }
+    }
+    let base = match task_settings!(mode, incremental) {
+        None => None,
+        Some(incremental) => {
+            match manifest::base(s3b, db_name, today, incremental.max_deltas()).await {
+                Ok(base) => base,
+                Err(err) => {
+                    ret.fail(None, ErrorClass::Manifest, err);
+                    return None;
+                }
+            }
+        }
+    };
+    if let Err(err) = manifest::delete(s3b, &db_prefix).await {
+        ret.fail(None, ErrorClass::Manifest, err);
+        return None;
+    }
+
+    let db_path = export::url_encode(db_name);
+    let (db_meta, security, design) =
+        match backup_design(client, s3b, &db_path, &db_prefix, compression, key).await {
+            Ok(ret) => ret,
+            Err((class, err)) => {
+                ret.fail(None, class, err);
+                return None;
+            }
+        };
+    Some(resume::DbProgress {
+        db_name: db_name.to_owned(),
+        started_at,
+        update_seq: db_info.update_seq,
+        doc_count: db_info.doc_count,
+        db_meta,
+        security,
+        design,
+        base,
+        chunks: vec![],
+        position: None,
+    })
+}
+
+/// Metadata (`GET /{db}`), `_security` and design docs of database; design docs are encoded like a chunk and uploaded
+/// as `${db_prefix}/design.json*`
+async fn backup_design(
+    client: &couch_rs::Client,
+    s3b: &s3_bucket::S3Bucket,
+    db_path: &str,
+    db_prefix: &str,
+    compression: encode::Compression,
+    key: Option<&encrypt::Key>,
+) -> std::result::Result<(manifest::DbMeta, serde_json::Value, manifest::Chunk), (ErrorClass, Error)>
+{
+    let export = |err| (ErrorClass::Export, err);
+    let db_meta = export::db_meta(client, db_path).await.map_err(export)?;
+    let security = export::security(client, db_path).await.map_err(export)?;
+    let docs = export::design_docs(client, db_path).await.map_err(export)?;
+
+    let mut encoder = ChunkEncoder::new(vec![], compression, key);
+    for doc in docs.iter() {
+        encoder
+            .write_doc(doc)
+            .await
+            .map_err(|err| (ErrorClass::Compress, err))?;
+    }
+    let (body, encoded) = encoder
+        .finish()
+        .await
+        .map_err(|err| (ErrorClass::Compress, err))?;
+    let key = bucket::design_key(db_prefix, compression.codec);
+    let object_to_upload = s3_bucket::ObjectToUploadBuilder::from_vecu8(body)
+        .content_length(Some(encoded.bytes_compressed as i64))
+        .build();
+    s3b.upload(key.clone(), object_to_upload)
+        .await
+        .map_err(|err| {
+            (
+                ErrorClass::Upload,
+                anyhow!("failed to upload {key:?}: {err}"),
+            )
+        })?;
+    let design = manifest::Chunk {
+        id: 0,
+        key: key[db_prefix.len() + 1..].to_owned(),
+        docs: encoded.docs,
+        bytes_raw: encoded.bytes_raw,
+        bytes_compressed: encoded.bytes_compressed,
+        sha256: encoded.sha256,
+    };
+    Ok((db_meta, security, design))
+}
+
+/// What `backup_db` does with database which `update_seq` and `doc_count` did not change since the last backup
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Unchanged {
+    /// Back it up anyway
+    #[default]
+    Backup,
+    /// Upload manifest of `manifest::Kind::Pointer` to the last backup instead, so backup of the day is complete
+    Pointer,
+    /// Do nothing
+    Skip,
+}
+
+/// Docs passed from fetch stage to encode-upload stage at once
+const DOCS_IN_FLIGHT: usize = 16;
+
+type Uploaded = std::result::Result<manifest::Chunk, (ErrorClass, Error)>;
+
+/// Encode-upload stage (chunk ids start at `first_chunk_id`): streams docs of each chunk through compressor into multipart upload of the chunk, so memory does not
+/// depend on size of chunk; returns manifest entry of each chunk. Each chunk is uploaded in span `chunk` (see
+/// `chunk_span`)
+async fn upload(
+    s3b: s3_bucket::S3Bucket,
+    db_prefix: String,
+    first_chunk_id: usize,
+    compression: encode::Compression,
+    key: Option<encrypt::Key>,
+    mut rx: mpsc::Receiver<Fetched>,
+) -> Vec<(usize, Uploaded)> {
+    let mut ret = vec![];
+    let mut chunk_id = first_chunk_id;
+    let mut current = None;
+    while let Some(fetched) = rx.recv().await {
+        let (span, start, encoder) = match current.take() {
+            Some(current) => current,
+            None => {
+                let span = chunk_span(chunk_id);
+                let encoder = start_chunk(&s3b, &db_prefix, chunk_id, compression, key.as_ref())
+                    .instrument(span.clone())
+                    .await;
+                (span, std::time::Instant::now(), encoder)
+            }
+        };
+        match fetched {
+            Fetched::Doc(doc) => {
+                let encoder = match encoder {
+                    Ok(mut encoder) => match encoder.write_doc(&doc).await {
+                        Ok(()) => Ok(encoder),
+                        Err(err) if err.is::<serde_json::Error>() => {
+                            Err((ErrorClass::Compress, err))
+                        }
+                        Err(err) => Err((ErrorClass::Upload, err)),
+                    },
+                    // docs of failed chunk are skipped, its upload is aborted as writer is dropped
+                    Err(err) => Err(err),
+                };
+                current = Some((span, start, encoder));
+            }
+            Fetched::ChunkEnd => {
+                let outcome = match encoder {
+                    Ok(encoder) => {
+                        finish_chunk(encoder, &db_prefix, chunk_id, compression.codec, start)
+                            .instrument(span)
+                            .await
+                    }
+                    Err(err) => Err(err),
+                };
+                ret.push((chunk_id, outcome));
+                chunk_id += 1;
+            }
+        }
+    }
+    ret
+}
+
+/// Span `chunk` of chunk `chunk_id` of `db` span; the rest of fields are recorded once it is uploaded
+fn chunk_span(chunk_id: usize) -> Span {
+    tracing::info_span!(
+        "chunk",
+        chunk_id,
+        docs = Empty,
+        bytes_raw = Empty,
+        bytes_compressed = Empty,
+        duration_ms = Empty,
+    )
+}
+
+async fn start_chunk(
+    s3b: &s3_bucket::S3Bucket,
+    db_prefix: &str,
+    chunk_id: usize,
+    compression: encode::Compression,
+    encryption_key: Option<&encrypt::Key>,
+) -> std::result::Result<ChunkEncoder<MultipartWriter>, (ErrorClass, Error)> {
+    let key = bucket::chunk_key(db_prefix, chunk_id, compression.codec);
+    s3b.multipart_writer(key, bucket::multipart_arg())
+        .await
+        .map(|writer| ChunkEncoder::new(writer, compression, encryption_key))
+        .map_err(|err| (ErrorClass::Upload, err))
+}
+
+async fn finish_chunk(
+    encoder: ChunkEncoder<MultipartWriter>,
+    db_prefix: &str,
+    chunk_id: usize,
+    codec: encode::Codec,
+    start: std::time::Instant,
+) -> Uploaded {
+    let key = bucket::chunk_key(db_prefix, chunk_id, codec);
+    let (_, encoded) = encoder
+        .finish()
+        .await
+        .map_err(|err| (ErrorClass::Upload, anyhow!("{key:?}: {err}")))?;
+    let duration = std::time::Instant::now().duration_since(start);
+    Span::current()
+        .record("docs", encoded.docs)
+        .record("bytes_raw", encoded.bytes_raw)
+        .record("bytes_compressed", encoded.bytes_compressed)
+        .record("duration_ms", duration.as_millis() as u64);
+    info!("did upload {key:?}");
+    Ok(manifest::Chunk {
+        id: chunk_id,
+        key: key[db_prefix.len() + 1..].to_owned(),
+        docs: encoded.docs,
+        bytes_raw: encoded.bytes_raw,
+        bytes_compressed: encoded.bytes_compressed,
+        sha256: encoded.sha256,
+    })
+}
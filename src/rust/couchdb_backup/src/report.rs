@@ -0,0 +1,283 @@
+#[allow(unused_imports)]
+use anyhow::{anyhow, bail, Context, Error, Result};
+
+use super::*;
+
+/// Exit codes of `couchdb_backup`
+pub mod exit_code {
+    /// Every selected database is backed up
+    pub const OK: i32 = 0;
+    /// Backup did not start or did not complete: bad config, CouchDB or S3 is not reachable, etc.
+    pub const ERROR: i32 = 1;
+    /// Backup of some databases failed, the rest are backed up (`on_failure: continue`)
+    pub const FAILED: i32 = 2;
+    /// Backup is stopped at the first failed database (`on_failure: fail_fast`)
+    pub const STOPPED: i32 = 3;
+    /// Time budget ran out: every database backed up so far is OK, the rest are left for the run with `continuation`
+    pub const SUSPENDED: i32 = 4;
+    /// Interrupted by Ctrl-C
+    pub const INTERRUPTED: i32 = 130;
+}
+
+/// What `run()` does when backup of a database fails
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FailurePolicy {
+    /// Back up the rest of databases, fail at the end
+    #[default]
+    Continue,
+    /// Stop at the first failed database
+    FailFast,
+}
+
+/// Stage of backup where failure happened
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorClass {
+    /// Reading docs from CouchDB (and uploading attachments stored separately)
+    Export,
+    /// Serializing and compressing a chunk
+    Compress,
+    /// Uploading a chunk to S3
+    Upload,
+    /// Uploading manifest to S3
+    Manifest,
+    /// Stage of pipeline panicked
+    Internal,
+}
+
+/// Result of `run()`
+#[derive(Debug, Clone, Serialize)]
+pub struct BackupReport {
+    pub mode: Mode,
+    pub policy: FailurePolicy,
+    pub databases: Vec<DbResult>,
+    /// Selected databases not backed up because they did not change since the last backup, see `pipeline::Unchanged`
+    pub skipped: Vec<String>,
+    /// Selected databases not processed because backup was stopped by `FailurePolicy::FailFast`
+    pub not_processed: Vec<String>,
+    /// Backup was stopped by Ctrl-C
+    pub interrupted: bool,
+    /// Token to resume backup stopped by time budget or by Ctrl-C with, see `resume`
+    pub continuation: Option<String>,
+    /// Selected databases left for the run resuming `continuation`, the first one may be partly backed up
+    pub suspended: Vec<String>,
+    /// Why backup did not start or did not complete (`run()` failed), see `exit_code::ERROR`
+    pub error: Option<String>,
+}
+
+/// Result of backup of a database
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct DbResult {
+    pub db_name: String,
+    pub docs: u64,
+    pub chunks: usize,
+    pub bytes_raw: u64,
+    pub bytes_compressed: u64,
+    pub failures: Vec<Failure>,
+    /// Database did not change since the last backup, so it is not backed up; reported in `BackupReport::skipped`
+    #[serde(skip)]
+    pub skipped: bool,
+    /// Backup stopped at deadline, goes on from here next run; reported in `BackupReport::suspended`
+    #[serde(skip)]
+    pub progress: Option<resume::DbProgress>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Failure {
+    pub db_name: String,
+    /// `None` if failure is not related to a particular chunk
+    pub chunk_id: Option<usize>,
+    pub class: ErrorClass,
+    pub error: String,
+}
+
+impl DbResult {
+    pub fn new(db_name: &str) -> Self {
+        Self {
+            db_name: db_name.to_owned(),
+            ..Default::default()
+        }
+    }
+    pub fn fail(
+        &mut self,
+        chunk_id: Option<usize>,
+        class: ErrorClass,
+        error: impl std::fmt::Display,
+    ) {
+        let error = error.to_string();
+        match chunk_id {
+            Some(chunk_id) => error!(
+                chunk_id,
+                "failed chunk {chunk_id:03} of db {:?} ({class:?}): {error}", self.db_name
+            ),
+            None => error!("failed db {:?} ({class:?}): {error}", self.db_name),
+        }
+        self.failures.push(Failure {
+            db_name: self.db_name.clone(),
+            chunk_id,
+            class,
+            error,
+        });
+    }
+    pub fn is_ok(&self) -> bool {
+        self.failures.is_empty()
+    }
+    /// Counts uploaded `chunk`
+    pub fn add(&mut self, chunk: &manifest::Chunk) {
+        self.chunks += 1;
+        self.docs += chunk.docs as u64;
+        self.bytes_raw += chunk.bytes_raw as u64;
+        self.bytes_compressed += chunk.bytes_compressed as u64;
+    }
+}
+
+impl BackupReport {
+    pub fn new(mode: Mode, policy: FailurePolicy) -> Self {
+        Self {
+            mode,
+            policy,
+            databases: vec![],
+            skipped: vec![],
+            not_processed: vec![],
+            interrupted: false,
+            continuation: None,
+            suspended: vec![],
+            error: None,
+        }
+    }
+    /// Report of backup which failed with `err` before it completed, so there is nothing else to report
+    pub fn of_error(mode: Mode, policy: FailurePolicy, err: &Error) -> Self {
+        Self {
+            error: Some(format!("{err:#}")),
+            ..Self::new(mode, policy)
+        }
+    }
+    pub fn failed(&self) -> impl Iterator<Item = &DbResult> {
+        self.databases.iter().filter(|db_result| !db_result.is_ok())
+    }
+    pub fn failures(&self) -> impl Iterator<Item = &Failure> {
+        self.databases
+            .iter()
+            .flat_map(|db_result| db_result.failures.iter())
+    }
+    pub fn is_ok(&self) -> bool {
+        self.error.is_none() && self.failed().next().is_none()
+    }
+    /// `INTERRUPTED` over `ERROR` over failure (`STOPPED` by `FailurePolicy::FailFast`, `FAILED` otherwise) over
+    /// `SUSPENDED`; `continuation` is reported whatever the code is
+    pub fn exit_code(&self) -> i32 {
+        if self.interrupted {
+            exit_code::INTERRUPTED
+        } else if self.error.is_some() {
+            exit_code::ERROR
+        } else if !self.is_ok() && !self.not_processed.is_empty() {
+            exit_code::STOPPED
+        } else if !self.is_ok() {
+            exit_code::FAILED
+        } else if self.continuation.is_some() {
+            exit_code::SUSPENDED
+        } else {
+            exit_code::OK
+        }
+    }
+    /// Databases selected by `run()`
+    pub fn selected(&self) -> usize {
+        self.databases.len() + self.skipped.len() + self.not_processed.len() + self.suspended.len()
+    }
+    /// Fails if backup of any database failed or backup was interrupted
+    pub fn ensure_ok(&self) -> Result<()> {
+        if self.interrupted {
+            return Err(anyhow!(delay::Interrupted)).with_context(|| {
+                format!(
+                    "{:?} backup stopped, {} database(s) left",
+                    self.mode,
+                    self.not_processed.len() + self.suspended.len()
+                )
+            });
+        }
+        if let Some(error) = self.error.as_ref() {
+            bail!("{:?} backup failed: {error}", self.mode);
+        }
+        let failed = self
+            .failed()
+            .map(|db_result| db_result.db_name.as_str())
+            .collect::<Vec<_>>();
+        if !failed.is_empty() {
+            bail!(
+                "{:?} backup failed for {} of {} database(s): {}",
+                self.mode,
+                failed.len(),
+                self.selected(),
+                failed.join(", ")
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Writes `value` as JSON to file `path`, or to stdout as a single line if `path` is `-`
+pub fn output(value: &impl Serialize, path: &std::path::Path) -> Result<()> {
+    if path == std::path::Path::new("-") {
+        println!("{}", serde_json::to_string(value)?);
+    } else {
+        std::fs::write(path, serde_json::to_vec_pretty(value)?)
+            .map_err(|err| anyhow!("failed to write report to {path:?}: {err}"))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_exit_code() {
+        let mut report = BackupReport::new(Mode::Weekly, FailurePolicy::FailFast);
+        report.databases.push(DbResult::new("a"));
+        report.skipped.push("unchanged".to_owned());
+        assert_eq!(report.exit_code(), exit_code::OK);
+        report.continuation = Some("file:/tmp/weekly.json".to_owned());
+        assert_eq!(report.exit_code(), exit_code::SUSPENDED);
+        let mut failed = DbResult::new("b");
+        failed.fail(Some(1), ErrorClass::Upload, "timeout");
+        report.databases.push(failed);
+        assert_eq!(report.exit_code(), exit_code::FAILED);
+        assert_eq!(report.failures().next().map(|f| f.chunk_id), Some(Some(1)));
+        report.continuation = None;
+        report.not_processed.push("c".to_owned());
+        assert_eq!(report.exit_code(), exit_code::STOPPED);
+        report.interrupted = true;
+        assert_eq!(report.exit_code(), exit_code::INTERRUPTED);
+    }
+
+    #[test]
+    fn test_exit_code_error() {
+        let report = BackupReport::of_error(
+            Mode::Monthly,
+            FailurePolicy::Continue,
+            &anyhow!("S3 is not ready"),
+        );
+        assert!(!report.is_ok());
+        assert_eq!(report.exit_code(), exit_code::ERROR);
+        assert!(report.ensure_ok().is_err());
+    }
+
+    #[test]
+    fn test_exit_code_failed_and_suspended() {
+        // `continue` policy: a database failed, then time budget ran out
+        let mut report = BackupReport::new(Mode::Weekly, FailurePolicy::Continue);
+        let mut failed = DbResult::new("a");
+        failed.fail(None, ErrorClass::Export, "no db");
+        report.databases.push(failed);
+        report.databases.push(DbResult::new("b"));
+        report.continuation = Some("file:/tmp/weekly.json".to_owned());
+        report.suspended = vec!["c".to_owned(), "d".to_owned()];
+        assert_eq!(report.exit_code(), exit_code::FAILED);
+        assert!(report.not_processed.is_empty());
+        assert_eq!(report.selected(), 4);
+        let json = serde_json::to_value(&report).unwrap();
+        assert_eq!(json["continuation"], "file:/tmp/weekly.json");
+        assert_eq!(json["suspended"], serde_json::json!(["c", "d"]));
+    }
+}
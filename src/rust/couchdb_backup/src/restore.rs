@@ -0,0 +1,426 @@
+#[allow(unused_imports)]
+use anyhow::{anyhow, bail, Context, Error, Result};
+
+use super::*;
+
+#[derive(Debug)]
+pub struct RestoreArg {
+    /// Name of database as it was backed up
+    pub db_name: String,
+    /// Date of backup (part of `${prefix}/${year}/${month}/${day}/${suffix}/${db_name}`)
+    pub date: chrono::NaiveDate,
+    /// Name of database to restore into, `db_name` if not set
+    pub target_db: Option<String>,
+    /// Url of CouchDB to restore into, `database.url` if not set
+    pub target_url: Option<String>,
+    /// Do not build view indexes after docs are restored
+    pub skip_views: bool,
+}
+
+/// Restores database in span `restore` (fields `db`, `target_db`, `duration_ms`)
+pub async fn run(arg: RestoreArg) -> Result<()> {
+    let span = tracing::info_span!(
+        "restore",
+        db = arg.db_name.as_str(),
+        target_db = arg.target_db.as_deref().unwrap_or(&arg.db_name),
+        duration_ms = tracing::field::Empty,
+    );
+    restore(arg).instrument(span).await
+}
+
+async fn restore(arg: RestoreArg) -> Result<()> {
+    let start = std::time::Instant::now();
+    let RestoreArg {
+        db_name,
+        date,
+        target_db,
+        target_url,
+        skip_views,
+    } = arg;
+
+    let s3b = bucket::s3_bucket()?;
+    let encryption_key = encrypt::key()?;
+    let db_prefix = bucket::db_prefix(&date, &db_name)?;
+    let manifest = manifest::download(&s3b, &db_prefix).await?;
+    // full backup and its deltas (and pointers), the latest first
+    let mut backups = vec![(db_prefix, manifest)];
+    while let Some((db_prefix, Some(manifest))) = backups.last() {
+        if manifest.kind == manifest::Kind::Full {
+            break;
+        }
+        let previous = manifest.previous.ok_or_else(|| {
+            anyhow!(
+                "{:?} at {db_prefix:?} has no previous backup",
+                manifest.kind
+            )
+        })?;
+        let db_prefix = bucket::db_prefix(&previous, &db_name)?;
+        let manifest = manifest::download(&s3b, &db_prefix).await?.ok_or_else(|| {
+            anyhow!("no complete manifest at {db_prefix:?}, which backup of {date} is based on")
+        })?;
+        backups.push((db_prefix, Some(manifest)));
+    }
+    backups.reverse();
+    backups.retain(|(db_prefix, manifest)| match manifest {
+        Some(manifest) if manifest.kind == manifest::Kind::Pointer => {
+            info!(
+                "db {db_name:?} did not change since {:?}, backup at {db_prefix:?} points to it",
+                manifest.previous
+            );
+            false
+        }
+        _ => true,
+    });
+    if backups.len() > 1 {
+        info!(
+            "backup of db {db_name:?} of {date} is incremental: will restore full backup at {:?} and {} delta(s)",
+            backups[0].0,
+            backups.len() - 1
+        );
+    }
+
+    // the latest backup holding docs has metadata, security and design docs to restore
+    let (db_meta, security, design) = backups
+        .last()
+        .and_then(|(db_prefix, manifest)| {
+            manifest.as_ref().map(|manifest| {
+                (
+                    Some(manifest.db_meta.clone()),
+                    Some(manifest.security.clone()),
+                    manifest
+                        .design
+                        .clone()
+                        .map(|design| (db_prefix.clone(), design)),
+                )
+            })
+        })
+        .unwrap_or_default();
+    // (db_prefix, key, sha256, attachments, whether docs are applied on top of ones restored before)
+    let mut restores = vec![];
+    for (db_prefix, manifest) in backups {
+        let is_delta = manifest
+            .as_ref()
+            .is_some_and(|manifest| manifest.kind == manifest::Kind::Delta);
+        let attachments = manifest
+            .as_ref()
+            .map(|manifest| manifest.attachments)
+            .unwrap_or_default();
+        for (key, sha256) in chunks(&s3b, &db_name, &db_prefix, manifest).await? {
+            restores.push((db_prefix.clone(), key, sha256, attachments, is_delta));
+        }
+    }
+    // after docs, so `validate_doc_update` of them does not reject docs being restored
+    if let Some((db_prefix, design)) = design {
+        restores.push((
+            db_prefix.clone(),
+            format!("{db_prefix}/{}", design.key),
+            Some(design.sha256),
+            export::Attachments::Inline,
+            true,
+        ));
+    }
+
+    let (client, uri) = couchdb_client(target_url.as_deref())?;
+    let target_db = target_db.unwrap_or(db_name.clone());
+    let target_path = export::url_encode(&target_db);
+    let db = create_db(&client, &uri, &target_db, db_meta.as_ref()).await?;
+    if let Some(security) = security.filter(|security| {
+        security
+            .as_object()
+            .is_some_and(|security| !security.is_empty())
+    }) {
+        client
+            .req(
+                reqwest::Method::PUT,
+                &format!("{target_path}/_security"),
+                None,
+            )
+            .json(&security)
+            .send()
+            .await
+            .and_then(|resp| resp.error_for_status())
+            .map_err(|err| anyhow!("failed to put {target_db:?}/_security: {err}"))?;
+        info!("did restore _security of db {target_db:?}");
+    }
+
+    let mut restored_count = 0;
+    let mut failed_count = 0;
+    for (db_prefix, key, sha256, attachments, with_revs) in restores {
+        let compressed = bucket::download(&s3b, &key).await?;
+        if let Some(sha256) = sha256 {
+            if manifest::sha256(&compressed) != sha256 {
+                bail!("{key:?} does not match sha256 of manifest");
+            }
+        }
+        let mut docs = decode_chunk(&key, compressed, encryption_key.as_ref())
+            .map_err(|err| anyhow!("{key:?}: {err}"))?;
+        for doc in docs.iter_mut() {
+            prepare_doc(doc, &s3b, &db_prefix, attachments, encryption_key.as_ref()).await?;
+        }
+        if with_revs {
+            apply_revs(&client, &target_path, &mut docs).await?;
+        }
+        if docs.is_empty() {
+            info!("nothing to restore from {key:?}");
+            continue;
+        }
+        let results = db
+            .bulk_docs(&mut docs)
+            .await
+            .map_err(|err| anyhow!("failed to post {key:?} to {target_db:?}/_bulk_docs: {err}"))?;
+        for result in results {
+            match result {
+                Ok(_) => restored_count += 1,
+                Err(err) => {
+                    failed_count += 1;
+                    error!("failed to restore doc from {key:?} to db {target_db:?}: {err}");
+                }
+            }
+        }
+        info!("did restore {key:?} to db {target_db:?}");
+    }
+
+    if failed_count > 0 {
+        bail!(
+            "failed to restore {failed_count} doc(s) to db {target_db:?} of {uri:?}, restored {restored_count} doc(s)"
+        );
+    }
+    if !skip_views {
+        views::rebuild(&client, &target_db).await?;
+    }
+    let duration = std::time::Instant::now().duration_since(start);
+    Span::current().record("duration_ms", duration.as_millis() as u64);
+    info!(
+        "OK: did restore {restored_count} doc(s) to db {target_db:?} of {uri:?} in {}",
+        arrange_millis::get(duration.as_millis()),
+    );
+    Ok(())
+}
+
+/// (key, sha256) of chunks of backup at `db_prefix`: as listed in `manifest` if it is there, found otherwise
+async fn chunks(
+    s3b: &s3_bucket::S3Bucket,
+    db_name: &str,
+    db_prefix: &str,
+    manifest: Option<manifest::Manifest>,
+) -> Result<Vec<(String, Option<String>)>> {
+    let attachments = manifest
+        .as_ref()
+        .map(|manifest| manifest.attachments)
+        .unwrap_or_default();
+    match manifest {
+        Some(manifest) => {
+            info!(
+                "found manifest of db {db_name:?} at {db_prefix:?}: {} chunk(s), made by couchdb_backup {}",
+                manifest.chunks.len(),
+                manifest.tool_version
+            );
+            Ok(manifest
+                .chunks
+                .into_iter()
+                .map(|chunk| (format!("{db_prefix}/{}", chunk.key), Some(chunk.sha256)))
+                .collect())
+        }
+        _ => {
+            info!(
+                "no complete manifest found at {db_prefix:?}, backup may be incomplete; will restore chunks found{}",
+                if attachments == export::Attachments::Inline {
+                    ", attachments are expected inline"
+                } else {
+                    ""
+                }
+            );
+            let mut keys = bucket::list_keys(s3b, &format!("{db_prefix}/"))
+                .await?
+                .into_iter()
+                .filter(|key| bucket::is_chunk_key(db_prefix, key))
+                .collect::<Vec<_>>();
+            keys.sort();
+            if keys.is_empty() {
+                bail!("no chunks found at {db_prefix:?}, check backup date and database name");
+            }
+            info!(
+                "found {} chunk(s) of db {db_name:?} at {db_prefix:?}",
+                keys.len()
+            );
+            Ok(keys.into_iter().map(|key| (key, None)).collect())
+        }
+    }
+}
+
+/// Sets `_rev` of docs of delta to current revision of them in `db_path`, so they update docs restored before;
+/// drops tombstones of docs which are not there
+async fn apply_revs(
+    client: &couch_rs::Client,
+    db_path: &str,
+    docs: &mut Vec<serde_json::Value>,
+) -> Result<()> {
+    #[derive(Deserialize)]
+    struct AllDocs {
+        rows: Vec<Row>,
+    }
+    #[derive(Deserialize)]
+    struct Row {
+        key: String,
+        value: Option<RowValue>,
+    }
+    #[derive(Deserialize)]
+    struct RowValue {
+        rev: String,
+        #[serde(default)]
+        deleted: bool,
+    }
+    let ids = docs
+        .iter()
+        .filter_map(|doc| doc.get("_id").and_then(|id| id.as_str()))
+        .collect::<Vec<_>>();
+    let all_docs = client
+        .req(reqwest::Method::POST, &format!("{db_path}/_all_docs"), None)
+        .json(&serde_json::json!({ "keys": ids }))
+        .send()
+        .await
+        .and_then(|resp| resp.error_for_status())
+        .map_err(|err| anyhow!("failed to post {db_path:?}/_all_docs: {err}"))?
+        .json::<AllDocs>()
+        .await
+        .map_err(|err| anyhow!("failed to parse {db_path:?}/_all_docs: {err}"))?;
+    let revs = all_docs
+        .rows
+        .into_iter()
+        .filter_map(|row| match row.value {
+            Some(RowValue {
+                rev,
+                deleted: false,
+            }) => Some((row.key, rev)),
+            _ => None,
+        })
+        .collect::<std::collections::HashMap<_, _>>();
+    docs.retain_mut(|doc| {
+        let is_tombstone = doc.get("_deleted").and_then(|deleted| deleted.as_bool()) == Some(true);
+        let rev = doc
+            .get("_id")
+            .and_then(|id| id.as_str())
+            .and_then(|id| revs.get(id));
+        match (rev, doc.as_object_mut()) {
+            (Some(rev), Some(doc)) => {
+                doc.insert("_rev".to_owned(), rev.clone().into());
+                true
+            }
+            _ => !is_tombstone,
+        }
+    });
+    Ok(())
+}
+
+/// Decrypts chunk `key` if it is encrypted, decompresses it with codec of its extension (gzip if unknown) and parses
+/// it as array of documents
+pub fn decode_chunk(
+    key: &str,
+    compressed: Vec<u8>,
+    encryption_key: Option<&encrypt::Key>,
+) -> Result<Vec<serde_json::Value>> {
+    let compressed = encrypt::decrypt(compressed, encryption_key)?;
+    encode::Codec::of_key(key)
+        .unwrap_or_default()
+        .decode(&compressed)
+}
+
+/// Like `create_db()` of couchdb_backup.sh: creates database if it does not exist, with shards and partitioning of
+/// `db_meta` if set
+async fn create_db(
+    client: &couch_rs::Client,
+    uri: &str,
+    db_name: &str,
+    db_meta: Option<&manifest::DbMeta>,
+) -> Result<couch_rs::database::Database> {
+    match client.get_info(db_name).await {
+        Ok(_) => {
+            info!("database {db_name:?} already exists at {uri:?}, its shards and partitioning are kept");
+        }
+        Err(err) if err.is_not_found() => {
+            let mut opts = std::collections::HashMap::new();
+            if let Some(db_meta) = db_meta {
+                if let Some(q) = db_meta.q {
+                    opts.insert("q".to_owned(), q.to_string());
+                }
+                if let Some(n) = db_meta.n {
+                    opts.insert("n".to_owned(), n.to_string());
+                }
+                if db_meta.partitioned {
+                    opts.insert("partitioned".to_owned(), "true".to_owned());
+                }
+            }
+            info!("will create database {db_name:?} at {uri:?} {opts:?}");
+            client
+                .req(
+                    reqwest::Method::PUT,
+                    &export::url_encode(db_name),
+                    Some(&opts),
+                )
+                .send()
+                .await
+                .and_then(|resp| resp.error_for_status())
+                .map_err(|err| anyhow!("failed to create db {db_name:?} of {uri:?}: {err}"))?;
+        }
+        Err(err) => bail!("failed to get info of db {db_name:?}: {err}"),
+    }
+    client
+        .db(db_name)
+        .await
+        .map_err(|err| anyhow!("failed to open db {db_name:?} of {uri:?}: {err}"))
+}
+
+/// Like `del(._rev)` of couchdb_backup.sh; also turns attachments into inline `{content_type, data}`,
+/// downloading them from S3 if they are stored separately, and drops stubs which bodies are not backed up
+async fn prepare_doc(
+    doc: &mut serde_json::Value,
+    s3b: &s3_bucket::S3Bucket,
+    db_prefix: &str,
+    attachments_mode: export::Attachments,
+    encryption_key: Option<&encrypt::Key>,
+) -> Result<()> {
+    use base64::Engine;
+    let Some(doc) = doc.as_object_mut() else {
+        return Ok(());
+    };
+    doc.remove("_rev");
+    let Some(attachments) = doc.remove("_attachments") else {
+        return Ok(());
+    };
+    let mut prepared = serde_json::Map::new();
+    for (name, attachment) in attachments.as_object().into_iter().flatten() {
+        let content_type = attachment
+            .get("content_type")
+            .cloned()
+            .unwrap_or_else(|| "application/octet-stream".into());
+        let data = match (
+            attachment.get("data"),
+            attachment.get("key").and_then(|key| key.as_str()),
+        ) {
+            (Some(data), _) => data.clone(),
+            (None, Some(key)) if attachments_mode == export::Attachments::Separate => {
+                let key = format!("{db_prefix}/{key}");
+                let body = encrypt::decrypt(bucket::download(s3b, &key).await?, encryption_key)
+                    .map_err(|err| anyhow!("{key:?}: {err}"))?;
+                base64::engine::general_purpose::STANDARD
+                    .encode(body)
+                    .into()
+            }
+            _ => {
+                warn!(
+                    "attachment {name:?} of doc {:?} is not backed up, will skip it",
+                    doc.get("_id")
+                );
+                continue;
+            }
+        };
+        prepared.insert(
+            name.clone(),
+            serde_json::json!({ "content_type": content_type, "data": data }),
+        );
+    }
+    if !prepared.is_empty() {
+        doc.insert("_attachments".to_owned(), prepared.into());
+    }
+    Ok(())
+}
@@ -0,0 +1,181 @@
+#[allow(unused_imports)]
+use anyhow::{anyhow, bail, Context, Error, Result};
+
+use super::*;
+
+/// Progress of backup stopped by time budget (`RunArg::budget`), saved by `save`; the next run goes on from it
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct State {
+    pub mode: Mode,
+    /// Databases left to back up, the first one is partly backed up if `db` is set
+    pub databases: Vec<String>,
+    pub db: Option<DbProgress>,
+    pub saved_at: chrono::DateTime<chrono::Utc>,
+}
+
+/// Partly done backup of database: chunks uploaded so far and what is needed to finish it the same way
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DbProgress {
+    pub db_name: String,
+    /// Date of backup is taken of it
+    pub started_at: chrono::DateTime<chrono::Utc>,
+    /// `update_seq` and `doc_count` of database when backup started
+    pub update_seq: String,
+    pub doc_count: u64,
+    pub db_meta: manifest::DbMeta,
+    pub security: serde_json::Value,
+    pub design: manifest::Chunk,
+    /// Base of delta, `None` for full backup
+    pub base: Option<manifest::Base>,
+    /// Uploaded chunks, the next one is `chunks.len()`
+    pub chunks: Vec<manifest::Chunk>,
+    /// `None` before the first chunk
+    pub position: Option<Position>,
+}
+
+/// Where backup of database goes on from
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Position {
+    /// `_id` of the last doc of `_all_docs` backed up (full backup)
+    LastId(String),
+    /// `_changes` seq backed up to (delta)
+    LastSeq(String),
+}
+
+/// Continuation token: where state is, `file:${state_file}` or `s3:${key}`
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Location {
+    File(std::path::PathBuf),
+    S3(String),
+}
+
+impl std::str::FromStr for Location {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self> {
+        if let Some(path) = s.strip_prefix("file:") {
+            Ok(Self::File(path.into()))
+        } else if let Some(key) = s.strip_prefix("s3:") {
+            Ok(Self::S3(key.to_owned()))
+        } else {
+            bail!("continuation token {s:?} is neither `file:...` nor `s3:...`")
+        }
+    }
+}
+
+impl std::fmt::Display for Location {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::File(path) => write!(f, "file:{}", path.display()),
+            Self::S3(key) => write!(f, "s3:{key}"),
+        }
+    }
+}
+
+/// `state_file` if set, `${bucket_prefix}/${prefix}/resume/${suffix}/${mode}.json` otherwise
+fn location(mode: Mode) -> Location {
+    if let Some(state_file) = settings!(state_file).clone() {
+        return Location::File(state_file.into());
+    }
+    let prefix = settings!(prefix).clone();
+    let suffix = settings!(suffix).clone();
+    let key = format!("{prefix}/resume/{suffix}/{mode}.json");
+    Location::S3(match bucket::bucket_url() {
+        Ok(bucket::BucketUrl { prefix, .. }) if !prefix.is_empty() => format!("{prefix}/{key}"),
+        _ => key,
+    })
+}
+
+/// Saves `state`, returns continuation token to `load` it with
+pub async fn save(s3b: &s3_bucket::S3Bucket, state: &State) -> Result<String> {
+    let location = location(state.mode);
+    let body = serde_json::to_vec_pretty(state)?;
+    match &location {
+        Location::File(path) => std::fs::write(path, body)
+            .map_err(|err| anyhow!("failed to write state to {path:?}: {err}"))?,
+        Location::S3(key) => {
+            let content_length = body.len() as i64;
+            let object_to_upload = s3_bucket::ObjectToUploadBuilder::from_vecu8(body)
+                .content_length(Some(content_length))
+                .content_type(Some("application/json".to_owned()))
+                .build();
+            s3b.upload(key.clone(), object_to_upload)
+                .await
+                .map_err(|err| anyhow!("failed to upload {key:?}: {err}"))?
+        }
+    }
+    Ok(location.to_string())
+}
+
+/// State of continuation `token`
+pub async fn load(s3b: &s3_bucket::S3Bucket, token: &str) -> Result<State> {
+    let location = token.parse::<Location>()?;
+    let body = match &location {
+        Location::File(path) => std::fs::read(path)
+            .map_err(|err| anyhow!("failed to read state from {path:?}: {err}"))?,
+        Location::S3(key) => bucket::download(s3b, key).await?,
+    };
+    serde_json::from_slice(&body)
+        .map_err(|err| anyhow!("failed to parse state of {token:?}: {err}"))
+}
+
+/// Removes state of continuation `token` once backup it was saved by is complete
+pub async fn clear(s3b: &s3_bucket::S3Bucket, token: &str) -> Result<()> {
+    match token.parse::<Location>()? {
+        Location::File(path) => std::fs::remove_file(&path)
+            .map_err(|err| anyhow!("failed to remove state {path:?}: {err}")),
+        Location::S3(key) => s3b
+            .delete(key.clone())
+            .await
+            .map(|_| ())
+            .map_err(|err| anyhow!("failed to delete {key:?}: {err}")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_location() {
+        for token in [
+            "file:/tmp/couchdb_backup.weekly.json",
+            "s3:folder/backup/ippbx/resume/couchdb/weekly.json",
+        ] {
+            assert_eq!(token.parse::<Location>().unwrap().to_string(), token);
+        }
+        assert!("/tmp/state.json".parse::<Location>().is_err());
+    }
+
+    #[test]
+    fn test_state() {
+        let state: State = serde_json::from_value(serde_json::json!({
+            "mode": "weekly",
+            "databases": ["account/ab/cd/0123", "account/ab/cd/4567"],
+            "db": {
+                "db_name": "account/ab/cd/0123",
+                "started_at": "2024-03-03T02:00:00Z",
+                "update_seq": "10-a",
+                "doc_count": 7,
+                "db_meta": { "q": 2, "n": 1 },
+                "security": {},
+                "design": {
+                    "id": 0, "key": "design.json.gz", "docs": 1,
+                    "bytes_raw": 10, "bytes_compressed": 20, "sha256": "",
+                },
+                "base": null,
+                "chunks": [],
+                "position": { "last_id": "doc-42" },
+            },
+            "saved_at": "2024-03-03T02:14:00Z",
+        }))
+        .unwrap();
+        let db = state.db.as_ref().unwrap();
+        assert_eq!(db.position, Some(Position::LastId("doc-42".to_owned())));
+        assert!(db.chunks.is_empty());
+        assert_eq!(
+            serde_json::from_slice::<State>(&serde_json::to_vec(&state).unwrap()).unwrap(),
+            state
+        );
+    }
+}
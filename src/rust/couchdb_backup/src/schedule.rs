@@ -0,0 +1,153 @@
+#[allow(unused_imports)]
+use anyhow::{anyhow, bail, Context, Error, Result};
+
+use super::*;
+use calendar::Calendar;
+use chrono::NaiveDateTime;
+
+const MODES: [Mode; 2] = [Mode::Weekly, Mode::Monthly];
+
+/// Calendar of each task, parsed from `task.weekly.cron` and `task.monthly.cron`
+pub fn tasks() -> Result<Vec<(Mode, Calendar)>> {
+    MODES
+        .into_iter()
+        .map(|mode| {
+            let cron = task_settings!(mode, cron);
+            cron.parse::<Calendar>()
+                .map(|calendar| (mode, calendar))
+                .map_err(|err| anyhow!("failed to parse cron {cron:?} of {mode:?} task: {err}"))
+        })
+        .collect()
+}
+
+fn now() -> NaiveDateTime {
+    chrono::Utc::now().naive_utc()
+}
+
+/// Runs tasks which calendar has elapsed within last `window` (to be started by systemd timer or cron),
+/// reports next elapse of the rest; returns report of each task run (with `error` if it failed to start)
+pub async fn scheduled(
+    window: std::time::Duration,
+    on_failure: Option<report::FailurePolicy>,
+) -> Result<Vec<report::BackupReport>> {
+    let now = now();
+    let since = now - chrono::Duration::from_std(window)?;
+    let mut due = vec![];
+    for (mode, calendar) in tasks()? {
+        match calendar.next_after(since) {
+            Some(at) if at <= now => {
+                info!("{mode:?} backup is due since {at} UTC");
+                due.push(mode);
+            }
+            next => info!("{mode:?} backup is not due now, {}", next_elapse(next)),
+        }
+    }
+    let mut reports = vec![];
+    for mode in due {
+        let report = run_task(mode, on_failure).await;
+        if let Some(error) = report.error.as_ref() {
+            error!("failed {mode:?} backup: {error}");
+        }
+        let interrupted = report.interrupted;
+        reports.push(report);
+        if interrupted {
+            break;
+        }
+    }
+    Ok(reports)
+}
+
+/// Runs every task at its calendar elapse until Ctrl-C (right after the running backup if elapse passed while it was
+/// running); writes report of each task run to `report_path` if set; serves `metrics` on `metrics.listen` if set
+pub async fn daemon(
+    on_failure: Option<report::FailurePolicy>,
+    report_path: Option<&std::path::Path>,
+) -> Result<()> {
+    let tasks = tasks()?;
+    if let Some(listen) = settings!(metrics)
+        .as_ref()
+        .and_then(|metrics| metrics.listen.clone())
+    {
+        metrics::serve(&listen)?;
+    }
+    let mut after = now();
+    loop {
+        let next = tasks
+            .iter()
+            .map(|(mode, calendar)| (*mode, calendar.next_after(after)))
+            .collect::<Vec<_>>();
+        for (mode, at) in next.iter() {
+            info!("{mode:?} backup: {}", next_elapse(*at));
+        }
+        let Some(at) = next.iter().filter_map(|(_, at)| *at).min() else {
+            bail!("no task is scheduled in foreseeable future");
+        };
+        let due = next
+            .into_iter()
+            .filter(|(_, next)| *next == Some(at))
+            .map(|(mode, _)| mode)
+            .collect::<Vec<_>>();
+        // elapse passed while previous backup was running
+        let sleep = match (at - now()).to_std() {
+            Ok(sleep) => sleep,
+            Err(_) => {
+                warn!(
+                    "{due:?} backup due at {at} UTC is late by {}, will run it now",
+                    arrange_millis::get((now() - at).num_milliseconds().max(0) as u128)
+                );
+                std::time::Duration::ZERO
+            }
+        };
+        if let Err(err) = delay::sleep(sleep, &delay::ctrl_c()).await {
+            info!("{err}, will exit");
+            return Ok(());
+        }
+        for mode in due {
+            let report = run_task(mode, on_failure).await;
+            if let Some(report_path) = report_path {
+                if let Err(err) = report::output(&report, report_path) {
+                    error!("{err}");
+                }
+            }
+            if let Err(err) = report.ensure_ok() {
+                if err.is::<delay::Interrupted>() {
+                    info!("{err:#}, will exit");
+                    return Ok(());
+                }
+                error!("{err}");
+            }
+        }
+        // not `now()`: elapses passed while backup was running are not skipped
+        after = at;
+    }
+}
+
+/// Runs task of `mode`; if it fails to start or complete, reports it as `BackupReport::error`, so the rest of tasks
+/// still run and reports of them are kept
+async fn run_task(mode: Mode, on_failure: Option<report::FailurePolicy>) -> report::BackupReport {
+    run(
+        mode,
+        RunArg {
+            on_failure,
+            ..Default::default()
+        },
+    )
+    .await
+    .unwrap_or_else(|err| {
+        report::BackupReport::of_error(
+            mode,
+            on_failure.or(settings!(on_failure)).unwrap_or_default(),
+            &err,
+        )
+    })
+}
+
+fn next_elapse(at: Option<NaiveDateTime>) -> String {
+    match at {
+        Some(at) => format!(
+            "next run at {at} UTC (in {})",
+            arrange_millis::get((at - now()).num_milliseconds().max(0) as u128)
+        ),
+        None => "no next run in foreseeable future".to_owned(),
+    }
+}
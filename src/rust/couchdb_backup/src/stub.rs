@@ -0,0 +1,67 @@
+//! HTTP/1.1 server stub for tests of CouchDB and Loki clients
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+/// Request received by `serve`
+#[derive(Debug)]
+pub struct Request {
+    pub method: String,
+    /// With query
+    pub path: String,
+    pub body: Vec<u8>,
+}
+
+/// Serves connection by connection on localhost in background, answering every request with `(status, JSON body)` of
+/// `respond`; returns `http://${addr}`
+pub async fn serve<F>(mut respond: F) -> String
+where
+    F: FnMut(Request) -> (u16, String) + Send + 'static,
+{
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            let Some(request) = read_request(&mut stream).await else {
+                continue;
+            };
+            let (status, body) = respond(request);
+            let resp = format!(
+                "HTTP/1.1 {status} Stub\r\ncontent-type: application/json\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{body}",
+                body.len()
+            );
+            let _ = stream.write_all(resp.as_bytes()).await;
+        }
+    });
+    url
+}
+
+/// Reads request up to the end of its body (`content-length`), so closing stream does not reset it; `None` if
+/// connection is closed before
+async fn read_request(stream: &mut TcpStream) -> Option<Request> {
+    let mut request = vec![];
+    let mut buf = [0u8; 4096];
+    let head_len = loop {
+        if let Some(pos) = request.windows(4).position(|w| w == b"\r\n\r\n") {
+            break pos + 4;
+        }
+        let n = stream.read(&mut buf).await.ok().filter(|n| *n > 0)?;
+        request.extend_from_slice(&buf[..n]);
+    };
+    let head = String::from_utf8_lossy(&request[..head_len]).into_owned();
+    let content_length = head
+        .lines()
+        .filter_map(|line| line.split_once(':'))
+        .find(|(name, _)| name.eq_ignore_ascii_case("content-length"))
+        .and_then(|(_, value)| value.trim().parse::<usize>().ok())
+        .unwrap_or_default();
+    while request.len() < head_len + content_length {
+        let n = stream.read(&mut buf).await.ok().filter(|n| *n > 0)?;
+        request.extend_from_slice(&buf[..n]);
+    }
+    let mut request_line = head.lines().next()?.split(' ');
+    Some(Request {
+        method: request_line.next()?.to_owned(),
+        path: request_line.next()?.to_owned(),
+        body: request[head_len..head_len + content_length].to_vec(),
+    })
+}
@@ -0,0 +1,384 @@
+#[allow(unused_imports)]
+use anyhow::{anyhow, bail, Context, Error, Result};
+
+use super::*;
+use std::collections::BTreeSet;
+
+/// Page size of `_all_docs` while collecting ids of live database
+const ALL_DOCS_PAGE: u64 = 10000;
+
+/// How many differing ids to print
+const SHOW_IDS: usize = 10;
+
+#[derive(Debug)]
+pub struct VerifyArg {
+    /// Name of database as it was backed up
+    pub db_name: String,
+    /// Date of backup (part of `${prefix}/${year}/${month}/${day}/${suffix}/${db_name}`)
+    pub date: chrono::NaiveDate,
+    /// Compare with manifest even if database exists
+    pub manifest_only: bool,
+}
+
+/// Re-reads every chunk of backup, checks that it decodes, then compares count and ids of docs with live database
+/// (with manifest if database is gone); in span `verify` (fields `db`, `duration_ms`), each problem found is logged as
+/// error
+pub async fn run(arg: VerifyArg) -> Result<()> {
+    let span = tracing::info_span!(
+        "verify",
+        db = arg.db_name.as_str(),
+        duration_ms = tracing::field::Empty,
+    );
+    verify(arg).instrument(span).await
+}
+
+async fn verify(arg: VerifyArg) -> Result<()> {
+    let start = std::time::Instant::now();
+    let VerifyArg {
+        db_name,
+        date,
+        manifest_only,
+    } = arg;
+
+    let s3b = bucket::s3_bucket()?;
+    let encryption_key = encrypt::key()?;
+    let mut db_prefix = bucket::db_prefix(&date, &db_name)?;
+    while let Some(manifest::Manifest {
+        kind: manifest::Kind::Pointer,
+        previous: Some(previous),
+        ..
+    }) = manifest::download(&s3b, &db_prefix).await?
+    {
+        info!(
+            "db {db_name:?} did not change since {previous}, backup at {db_prefix:?} points to it"
+        );
+        db_prefix = bucket::db_prefix(&previous, &db_name)?;
+    }
+    let listed = bucket::list_keys(&s3b, &format!("{db_prefix}/"))
+        .await?
+        .into_iter()
+        .collect::<BTreeSet<_>>();
+    let manifest = manifest::download(&s3b, &db_prefix).await?;
+    let (keys, mut problems) = check_chunks(&db_prefix, &listed, manifest.as_ref())?;
+    info!(
+        "found {} chunk(s) of db {db_name:?} at {db_prefix:?}, {}",
+        keys.len(),
+        if manifest.is_some() {
+            "with manifest"
+        } else {
+            "without manifest"
+        }
+    );
+
+    let mut ids = BTreeSet::new();
+    let mut docs_count = 0;
+    for key in keys.iter() {
+        let compressed = match bucket::download(&s3b, key).await {
+            Ok(compressed) => compressed,
+            Err(err) => {
+                problems.push(err.to_string());
+                continue;
+            }
+        };
+        let chunk = manifest.as_ref().and_then(|manifest| {
+            manifest
+                .chunks
+                .iter()
+                .find(|chunk| key.strip_prefix(&db_prefix) == Some(&format!("/{}", chunk.key)))
+        });
+        if let Some(chunk) = chunk {
+            if manifest::sha256(&compressed) != chunk.sha256 {
+                problems.push(format!("{key:?} does not match sha256 of manifest"));
+            }
+        }
+        let docs = match restore::decode_chunk(key, compressed, encryption_key.as_ref()) {
+            Ok(docs) => docs,
+            Err(err) => {
+                problems.push(format!("{key:?}: {err}"));
+                continue;
+            }
+        };
+        if let Some(chunk) = chunk {
+            if docs.len() != chunk.docs {
+                problems.push(format!(
+                    "{key:?} has {} doc(s), manifest says {}",
+                    docs.len(),
+                    chunk.docs
+                ));
+            }
+        }
+        docs_count += docs.len();
+        for doc in docs {
+            for attachment in attachment_keys(&doc) {
+                let attachment = format!("{db_prefix}/{attachment}");
+                if !listed.contains(&attachment) {
+                    problems.push(format!("attachment {attachment:?} of {key:?} is missing"));
+                }
+            }
+            match doc.get("_id").and_then(|id| id.as_str()) {
+                Some(id) => {
+                    if !ids.insert(id.to_owned()) {
+                        problems.push(format!("doc {id:?} of {key:?} is duplicated"));
+                    }
+                }
+                None => problems.push(format!("{key:?} has a doc without _id")),
+            }
+        }
+        info!("did verify {key:?}");
+    }
+    // design docs are backed up apart from chunks
+    if let Some(design) = manifest
+        .as_ref()
+        .and_then(|manifest| manifest.design.as_ref())
+    {
+        let key = format!("{db_prefix}/{}", design.key);
+        let docs = bucket::download(&s3b, &key).await.and_then(|compressed| {
+            if manifest::sha256(&compressed) != design.sha256 {
+                bail!("does not match sha256 of manifest");
+            }
+            restore::decode_chunk(&key, compressed, encryption_key.as_ref())
+        });
+        match docs {
+            Ok(docs) => {
+                ids.extend(
+                    docs.iter()
+                        .filter_map(|doc| doc.get("_id").and_then(|id| id.as_str()))
+                        .map(|id| id.to_owned()),
+                );
+                info!("did verify {key:?}: {} design doc(s)", docs.len());
+            }
+            Err(err) => problems.push(format!("{key:?}: {err}")),
+        }
+    }
+
+    let (client, uri) = couchdb_client(None)?;
+    let is_delta = manifest
+        .as_ref()
+        .is_some_and(|manifest| manifest.kind == manifest::Kind::Delta);
+    if is_delta && !manifest_only {
+        info!("backup is a delta, will compare it with manifest rather than db {db_name:?}");
+    }
+    let db_exists = if manifest_only || is_delta {
+        false
+    } else {
+        match client.get_info(&db_name).await {
+            Ok(_) => true,
+            Err(err) if err.is_not_found() => false,
+            Err(err) => bail!("failed to get info of db {db_name:?}: {err}"),
+        }
+    };
+    if db_exists {
+        let live = live_ids(&client, &db_name).await?;
+        info!(
+            "backup has {docs_count} doc(s), db {db_name:?} of {uri:?} has {}",
+            live.len()
+        );
+        if let Some(problem) = id_difference("not backed up", live.difference(&ids)) {
+            problems.push(problem);
+        }
+        if let Some(problem) = id_difference("not in db", ids.difference(&live)) {
+            problems.push(problem);
+        }
+    } else if let Some(manifest) = manifest.as_ref() {
+        let expected = manifest
+            .chunks
+            .iter()
+            .map(|chunk| chunk.docs)
+            .sum::<usize>();
+        info!(
+            "backup has {docs_count} doc(s), manifest says {expected} (doc_count was {} when backup started)",
+            manifest.doc_count
+        );
+        if docs_count != expected {
+            problems.push(format!(
+                "backup has {docs_count} doc(s), manifest says {expected}"
+            ));
+        }
+    } else {
+        problems.push(format!(
+            "db {db_name:?} is gone and backup has no manifest, nothing to compare {docs_count} doc(s) with"
+        ));
+    }
+
+    if !problems.is_empty() {
+        for problem in problems.iter() {
+            error!("{problem}");
+        }
+        bail!(
+            "backup of db {db_name:?} at {db_prefix:?} has {} problem(s)",
+            problems.len()
+        );
+    }
+    let duration = std::time::Instant::now().duration_since(start);
+    Span::current().record("duration_ms", duration.as_millis() as u64);
+    info!(
+        "OK: did verify {docs_count} doc(s) of db {db_name:?} at {db_prefix:?} in {}",
+        arrange_millis::get(duration.as_millis()),
+    );
+    Ok(())
+}
+
+/// Sorted chunk keys among `listed` keys of `db_prefix` and their differences from `manifest`;
+/// fails if there are none unless `manifest` has no chunks either (backup of empty db)
+fn check_chunks(
+    db_prefix: &str,
+    listed: &BTreeSet<String>,
+    manifest: Option<&manifest::Manifest>,
+) -> Result<(Vec<String>, Vec<String>)> {
+    let keys = listed
+        .iter()
+        .filter(|key| bucket::is_chunk_key(db_prefix, key))
+        .cloned()
+        .collect::<Vec<_>>();
+    if keys.is_empty() && !manifest.is_some_and(|manifest| manifest.chunks.is_empty()) {
+        bail!("no chunks found at {db_prefix:?}, check backup date and database name");
+    }
+    let mut problems = vec![];
+    if let Some(manifest) = manifest {
+        let listed = keys.iter().cloned().collect::<BTreeSet<_>>();
+        let expected = manifest
+            .chunks
+            .iter()
+            .map(|chunk| format!("{db_prefix}/{}", chunk.key))
+            .collect::<BTreeSet<_>>();
+        for key in expected.difference(&listed) {
+            problems.push(format!("{key:?} is listed in manifest but is missing"));
+        }
+        for key in listed.difference(&expected) {
+            problems.push(format!("{key:?} is not listed in manifest"));
+        }
+    }
+    Ok((keys, problems))
+}
+
+/// Keys (relative to `${db_prefix}`) of attachments of `doc` stored separately, see `export::Attachments::Separate`
+fn attachment_keys(doc: &serde_json::Value) -> impl Iterator<Item = &str> {
+    doc.get("_attachments")
+        .and_then(|attachments| attachments.as_object())
+        .into_iter()
+        .flat_map(|attachments| attachments.values())
+        .filter_map(|attachment| attachment.get("key").and_then(|key| key.as_str()))
+}
+
+async fn live_ids(client: &couch_rs::Client, db_name: &str) -> Result<BTreeSet<String>> {
+    let db_path = export::url_encode(db_name);
+    let mut ret = BTreeSet::new();
+    let mut startkey = None;
+    loop {
+        let ids = export::all_docs_ids(client, &db_path, Some(ALL_DOCS_PAGE), startkey.as_deref())
+            .await?;
+        let is_last = (ids.len() as u64) < ALL_DOCS_PAGE;
+        startkey = ids.last().cloned();
+        ret.extend(ids);
+        if is_last {
+            break;
+        }
+    }
+    Ok(ret)
+}
+
+/// Describes `ids` (first `SHOW_IDS` of them) if there are any
+fn id_difference<'a>(what: &str, ids: impl Iterator<Item = &'a String>) -> Option<String> {
+    let ids = ids.collect::<Vec<_>>();
+    if ids.is_empty() {
+        return None;
+    }
+    Some(format!(
+        "{} doc(s) {what}: {:?}{}",
+        ids.len(),
+        ids.iter().take(SHOW_IDS).collect::<Vec<_>>(),
+        if ids.len() > SHOW_IDS { ", ..." } else { "" }
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_id_difference() {
+        let a = ["1", "2", "3"]
+            .into_iter()
+            .map(|s| s.to_owned())
+            .collect::<BTreeSet<_>>();
+        let b = ["2"]
+            .into_iter()
+            .map(|s| s.to_owned())
+            .collect::<BTreeSet<_>>();
+        assert_eq!(id_difference("missing", b.difference(&a)), None);
+        assert_eq!(
+            id_difference("missing", a.difference(&b)).as_deref(),
+            Some(r#"2 doc(s) missing: ["1", "3"]"#)
+        );
+    }
+
+    #[test]
+    fn test_check_chunks() {
+        let db_prefix = "backup/ippbx/2024/03/02/couchdb/account/ab/cd/0123";
+        let manifest = |chunks: serde_json::Value| -> manifest::Manifest {
+            serde_json::from_value(serde_json::json!({
+                "tool_version": "0.13.0",
+                "db_name": "account/ab/cd/0123",
+                "kind": "full",
+                "chain": 0,
+                "update_seq": "0-a",
+                "doc_count": 0,
+                "attachments": "inline",
+                "compression": { "codec": "gzip" },
+                "db_meta": {},
+                "security": {},
+                "started_at": "2024-03-02T02:00:00Z",
+                "finished_at": "2024-03-02T02:00:01Z",
+                "chunks": chunks,
+            }))
+            .unwrap()
+        };
+        // backup of empty db
+        let listed = [format!("{db_prefix}/manifest.json")]
+            .into_iter()
+            .collect::<BTreeSet<_>>();
+        let (keys, problems) =
+            check_chunks(db_prefix, &listed, Some(&manifest(serde_json::json!([])))).unwrap();
+        assert!(keys.is_empty());
+        assert!(problems.is_empty());
+        assert!(check_chunks(db_prefix, &BTreeSet::new(), None).is_err());
+
+        let chunk = serde_json::json!([{
+            "id": 0,
+            "key": "000.json.gz",
+            "docs": 1,
+            "bytes_raw": 10,
+            "bytes_compressed": 10,
+            "sha256": "",
+        }]);
+        assert!(check_chunks(db_prefix, &listed, Some(&manifest(chunk.clone()))).is_err());
+        let listed = [
+            format!("{db_prefix}/001.json.gz"),
+            format!("{db_prefix}/attachments/a/b"),
+        ]
+        .into_iter()
+        .collect::<BTreeSet<_>>();
+        let (keys, problems) = check_chunks(db_prefix, &listed, Some(&manifest(chunk))).unwrap();
+        assert_eq!(keys, vec![format!("{db_prefix}/001.json.gz")]);
+        assert_eq!(problems.len(), 2);
+    }
+
+    #[test]
+    fn test_attachment_keys() {
+        let doc = serde_json::json!({
+            "_id": "a",
+            "_attachments": {
+                "b": { "stub": true, "key": "attachments/a/b" },
+                "c": { "content_type": "text/plain", "data": "" },
+            },
+        });
+        assert_eq!(
+            attachment_keys(&doc).collect::<Vec<_>>(),
+            vec!["attachments/a/b"]
+        );
+        assert_eq!(
+            attachment_keys(&serde_json::json!({ "_id": "a" })).count(),
+            0
+        );
+    }
+}
@@ -0,0 +1,353 @@
+#[allow(unused_imports)]
+use anyhow::{anyhow, bail, Context, Error, Result};
+
+use super::*;
+use std::time::Duration;
+
+/// View of design doc, `ddoc/view` in `views.list`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct View {
+    /// Name of design doc without `_design/`
+    pub ddoc: String,
+    pub view: String,
+}
+
+impl std::fmt::Display for View {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}/{}", self.ddoc, self.view)
+    }
+}
+
+impl std::str::FromStr for View {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self> {
+        let s = s.strip_prefix(export::DESIGN_PREFIX).unwrap_or(s);
+        match s.split_once('/') {
+            Some((ddoc, view)) if !ddoc.is_empty() && !view.is_empty() && !view.contains('/') => {
+                Ok(Self {
+                    ddoc: ddoc.to_owned(),
+                    view: view.to_owned(),
+                })
+            }
+            _ => bail!("view {s:?} is not `ddoc/view`"),
+        }
+    }
+}
+
+/// Views of `_design` docs of database; Mango indexes (`"language": "query"`) are skipped
+pub async fn discover(client: &couch_rs::Client, db_path: &str) -> Result<Vec<View>> {
+    #[derive(Deserialize)]
+    struct DesignDocs {
+        rows: Vec<Row>,
+    }
+    #[derive(Deserialize)]
+    struct Row {
+        doc: Option<serde_json::Value>,
+    }
+    let path = format!("{db_path}/_design_docs");
+    let opts = [("include_docs".to_owned(), "true".to_owned())]
+        .into_iter()
+        .collect();
+    let design_docs = client
+        .req(reqwest::Method::GET, &path, Some(&opts))
+        .send()
+        .await
+        .and_then(|resp| resp.error_for_status())
+        .map_err(|err| anyhow!("failed to get {path:?}: {err}"))?
+        .json::<DesignDocs>()
+        .await
+        .map_err(|err| anyhow!("failed to parse {path:?}: {err}"))?;
+    Ok(design_docs
+        .rows
+        .iter()
+        .filter_map(|row| row.doc.as_ref())
+        .flat_map(views_of)
+        .collect())
+}
+
+fn views_of(doc: &serde_json::Value) -> Vec<View> {
+    let Some(ddoc) = doc
+        .get("_id")
+        .and_then(|id| id.as_str())
+        .and_then(|id| id.strip_prefix(export::DESIGN_PREFIX))
+    else {
+        return vec![];
+    };
+    if doc.get("language").and_then(|language| language.as_str()) == Some("query") {
+        return vec![];
+    }
+    doc.get("views")
+        .and_then(|views| views.as_object())
+        .map(|views| {
+            views
+                .keys()
+                .map(|view| View {
+                    ddoc: ddoc.to_owned(),
+                    view: view.clone(),
+                })
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Views of `list` which are in `discovered`, and the rest
+fn partition(list: Vec<View>, discovered: &[View]) -> (Vec<View>, Vec<View>) {
+    list.into_iter().partition(|view| discovered.contains(view))
+}
+
+/// Builds indexes of `views.list` (all views of database if not set), `views.concurrency` at once: queries each
+/// view, and while query does not respond within `views.poll`, waits for `_active_tasks` to have no indexer of its
+/// design doc left. Views of `views.list` which database lacks are skipped; views which failed to build fail it only
+/// if `views.fatal` is set
+pub async fn rebuild(client: &couch_rs::Client, db_name: &str) -> Result<()> {
+    let start = std::time::Instant::now();
+    let settings = settings!(views).clone().unwrap_or_default();
+    let db_path = export::url_encode(db_name);
+    let discovered = match discover(client, &db_path).await {
+        Ok(discovered) => discovered,
+        Err(err) if !settings.fatal => {
+            warn!("{err}, views of db {db_name:?} will be built on query");
+            return Ok(());
+        }
+        Err(err) => return Err(err),
+    };
+    let views = match settings.list.as_ref() {
+        Some(list) => {
+            let list = list
+                .iter()
+                .map(|s| s.parse())
+                .collect::<Result<Vec<View>>>()?;
+            let (views, missing) = partition(list, &discovered);
+            for view in missing {
+                warn!("db {db_name:?} has no view {view} of `views.list`, skipped");
+            }
+            views
+        }
+        None => discovered,
+    };
+    if views.is_empty() {
+        info!("no views to build in db {db_name:?}");
+        return Ok(());
+    }
+    let concurrency = settings.concurrency();
+    let poll = Duration::from_secs(settings.poll());
+    info!(
+        "will build {} view(s) of db {db_name:?}, {concurrency} at once",
+        views.len()
+    );
+
+    let mut views = views.into_iter();
+    let mut building = tokio::task::JoinSet::new();
+    let mut failed_count = 0;
+    loop {
+        while building.len() < concurrency {
+            let Some(view) = views.next() else {
+                break;
+            };
+            let client = client.clone();
+            let db_name = db_name.to_owned();
+            building.spawn(
+                async move {
+                    let start = std::time::Instant::now();
+                    let ret = build(&client, &db_name, &view, poll).await;
+                    (view, ret, std::time::Instant::now().duration_since(start))
+                }
+                .instrument(Span::current()),
+            );
+        }
+        let Some(joined) = building.join_next().await else {
+            break;
+        };
+        let (view, ret, elapsed) = joined?;
+        match ret {
+            Ok(()) => info!(
+                "did build view {view} of db {db_name:?} in {}",
+                arrange_millis::get(elapsed.as_millis())
+            ),
+            Err(err) => {
+                failed_count += 1;
+                warn!(
+                    "failed to build view {view} of db {db_name:?} in {}: {err}",
+                    arrange_millis::get(elapsed.as_millis())
+                );
+            }
+        }
+    }
+    if failed_count > 0 {
+        if settings.fatal {
+            bail!("failed to build {failed_count} view(s) of db {db_name:?}");
+        }
+        warn!(
+            "failed to build {failed_count} view(s) of db {db_name:?}, they will be built on query"
+        );
+        return Ok(());
+    }
+    info!(
+        "did build views of db {db_name:?} in {}",
+        arrange_millis::get(std::time::Instant::now().duration_since(start).as_millis())
+    );
+    Ok(())
+}
+
+async fn build(
+    client: &couch_rs::Client,
+    db_name: &str,
+    view: &View,
+    poll: Duration,
+) -> Result<()> {
+    let path = format!(
+        "{}/_design/{}/_view/{}",
+        export::url_encode(db_name),
+        export::url_encode(&view.ddoc),
+        export::url_encode(&view.view)
+    );
+    let opts = [("limit".to_owned(), "1".to_owned())].into_iter().collect();
+    let design_document = format!("{}{}", export::DESIGN_PREFIX, view.ddoc);
+    loop {
+        // responds once index is up to date; CouchDB goes on building it if request times out
+        match client
+            .req(reqwest::Method::GET, &path, Some(&opts))
+            .timeout(poll)
+            .send()
+            .await
+            .and_then(|resp| resp.error_for_status())
+        {
+            Ok(_) => return Ok(()),
+            Err(err) if err.is_timeout() => {}
+            Err(err) => bail!("failed to get {path:?}: {err}"),
+        }
+        while let Some(progress) = indexing(client, db_name, &design_document).await? {
+            info!("view {view} of db {db_name:?} is being built: {progress}% done");
+            delay::sleep(poll, &delay::ctrl_c()).await?;
+        }
+    }
+}
+
+/// Least `progress` of indexers of `design_document` of `db_name` in `_active_tasks`, `None` if there are none
+async fn indexing(
+    client: &couch_rs::Client,
+    db_name: &str,
+    design_document: &str,
+) -> Result<Option<u64>> {
+    let active_tasks = client
+        .req(reqwest::Method::GET, "/_active_tasks", None)
+        .send()
+        .await
+        .and_then(|resp| resp.error_for_status())
+        .map_err(|err| anyhow!("failed to get _active_tasks: {err}"))?
+        .json::<Vec<serde_json::Value>>()
+        .await
+        .map_err(|err| anyhow!("failed to parse _active_tasks: {err}"))?;
+    Ok(active_tasks
+        .iter()
+        .filter(|task| is_indexer_of(task, db_name, design_document))
+        .map(|task| {
+            task.get("progress")
+                .and_then(|progress| progress.as_u64())
+                .unwrap_or(0)
+        })
+        .min())
+}
+
+/// Task of `_active_tasks` builds index of `design_document` in `db_name` or its shard
+/// (`shards/00000000-1fffffff/{db_name}.1700000000`)
+fn is_indexer_of(task: &serde_json::Value, db_name: &str, design_document: &str) -> bool {
+    let field = |name| task.get(name).and_then(|value| value.as_str());
+    if field("type") != Some("indexer") || field("design_document") != Some(design_document) {
+        return false;
+    }
+    let Some(database) = field("database") else {
+        return false;
+    };
+    let database = database
+        .strip_prefix("shards/")
+        .and_then(|s| s.split_once('/'))
+        .and_then(|(_, s)| s.rsplit_once('.'))
+        .map(|(database, _)| database)
+        .unwrap_or(database);
+    database == db_name
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_view_from_str() {
+        let view = View {
+            ddoc: "cdrs".to_owned(),
+            view: "crossbar_listing".to_owned(),
+        };
+        assert_eq!("cdrs/crossbar_listing".parse::<View>().unwrap(), view);
+        assert_eq!(
+            "_design/cdrs/crossbar_listing".parse::<View>().unwrap(),
+            view
+        );
+        assert!("cdrs".parse::<View>().is_err());
+        assert!("cdrs/".parse::<View>().is_err());
+        assert!("a/b/c".parse::<View>().is_err());
+    }
+
+    #[test]
+    fn test_views_of() {
+        let doc = serde_json::json!({
+            "_id": "_design/cdrs",
+            "views": { "crossbar_listing": { "map": "" }, "summarize_cdrs": { "map": "" } },
+        });
+        assert_eq!(
+            views_of(&doc)
+                .iter()
+                .map(|view| view.to_string())
+                .collect::<Vec<_>>(),
+            ["cdrs/crossbar_listing", "cdrs/summarize_cdrs"]
+        );
+        let mango = serde_json::json!({
+            "_id": "_design/idx", "language": "query", "views": { "by_name": {} },
+        });
+        assert!(views_of(&mango).is_empty());
+    }
+
+    #[test]
+    fn test_partition() {
+        let view = |s: &str| s.parse::<View>().unwrap();
+        let discovered = [view("cdrs/crossbar_listing"), view("cdrs/summarize_cdrs")];
+        let (views, missing) = partition(
+            vec![
+                view("cdrs/crossbar_listing"),
+                view("recordings/listing_by_user"),
+                view("cdrs/no_such_view"),
+            ],
+            &discovered,
+        );
+        assert_eq!(views, [view("cdrs/crossbar_listing")]);
+        assert_eq!(
+            missing,
+            [
+                view("recordings/listing_by_user"),
+                view("cdrs/no_such_view")
+            ]
+        );
+    }
+
+    #[test]
+    fn test_is_indexer_of() {
+        let task = serde_json::json!({
+            "type": "indexer",
+            "database": "shards/00000000-1fffffff/account/ab/cd/0123-202403.1700000000",
+            "design_document": "_design/cdrs",
+            "progress": 42,
+        });
+        let db_name = "account/ab/cd/0123-202403";
+        assert!(is_indexer_of(&task, db_name, "_design/cdrs"));
+        assert!(!is_indexer_of(&task, db_name, "_design/recordings"));
+        assert!(!is_indexer_of(&task, "other", "_design/cdrs"));
+        let task = serde_json::json!({
+            "type": "indexer", "database": "local", "design_document": "_design/cdrs",
+        });
+        assert!(is_indexer_of(&task, "local", "_design/cdrs"));
+        let task = serde_json::json!({
+            "type": "replication", "database": "local", "design_document": "_design/cdrs",
+        });
+        assert!(!is_indexer_of(&task, "local", "_design/cdrs"));
+    }
+}
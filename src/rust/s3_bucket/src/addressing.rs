@@ -0,0 +1,96 @@
+#[allow(unused_imports)]
+use anyhow::{anyhow, bail, Context, Error, Result};
+
+use rusoto_core::credential::{Anonymous, ProvideAwsCredentials};
+use rusoto_core::request::{
+    DispatchSignedRequest, DispatchSignedRequestFuture, HttpClient, HttpDispatchError,
+};
+use rusoto_core::signature::SignedRequest;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// How bucket is addressed in requests
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Addressing {
+    /// `https://${endpoint}/${bucket}/${key}`, the only one rusoto_s3 does itself
+    #[default]
+    Path,
+    /// `https://${bucket}.${endpoint}/${key}`; bucket name must be a valid host label (no dots for https)
+    VirtualHost,
+}
+
+/// Moves bucket from path to host of unsigned request and signs it: rusoto_s3 signs path-style requests only,
+/// so `S3Client` for `Addressing::VirtualHost` is built on not signing `rusoto_core::Client` with this dispatcher
+pub struct VirtualHostDispatcher<P> {
+    inner: Arc<HttpClient>,
+    provider: Arc<P>,
+    bucket: String,
+}
+
+impl<P> VirtualHostDispatcher<P> {
+    pub fn new(inner: HttpClient, provider: P, bucket: String) -> Self {
+        Self {
+            inner: Arc::new(inner),
+            provider: Arc::new(provider),
+            bucket,
+        }
+    }
+}
+
+/// Path of request to `bucket` with virtual-host addressing
+fn virtual_host_path(path: &str, bucket: &str) -> String {
+    match path
+        .strip_prefix('/')
+        .and_then(|path| path.strip_prefix(bucket))
+    {
+        Some("") => "/".to_owned(),
+        Some(rest) if rest.starts_with('/') => rest.to_owned(),
+        _ => path.to_owned(),
+    }
+}
+
+impl<P> DispatchSignedRequest for VirtualHostDispatcher<P>
+where
+    P: ProvideAwsCredentials + Send + Sync + 'static,
+{
+    fn dispatch(
+        &self,
+        mut request: SignedRequest,
+        timeout: Option<Duration>,
+    ) -> DispatchSignedRequestFuture {
+        let inner = self.inner.clone();
+        let provider = self.provider.clone();
+        let hostname = format!("{}.{}", self.bucket, request.hostname());
+        request.path = virtual_host_path(&request.path, &self.bucket);
+        request.set_hostname(Some(hostname));
+        Box::pin(async move {
+            let credentials = provider
+                .credentials()
+                .await
+                .map_err(|err| HttpDispatchError::new(err.to_string()))?;
+            if credentials.is_anonymous() {
+                request.complement();
+            } else {
+                request.sign(&credentials);
+            }
+            inner.dispatch(request, timeout).await
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_virtual_host_path() {
+        assert_eq!(virtual_host_path("/backup", "backup"), "/");
+        assert_eq!(virtual_host_path("/backup/", "backup"), "/");
+        assert_eq!(
+            virtual_host_path("/backup/2023/01/db/000.json.gz", "backup"),
+            "/2023/01/db/000.json.gz"
+        );
+        assert_eq!(virtual_host_path("/backups/a", "backup"), "/backups/a");
+    }
+}
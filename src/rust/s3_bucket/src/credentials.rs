@@ -0,0 +1,327 @@
+#[allow(unused_imports)]
+use anyhow::{anyhow, bail, Context, Error, Result};
+
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+
+use async_trait::async_trait;
+use rusoto_core::credential::{
+    AutoRefreshingProvider, AwsCredentials, ContainerProvider, CredentialsError,
+    EnvironmentProvider, ProfileProvider, ProvideAwsCredentials,
+};
+use std::time::Duration;
+
+const IMDS_ENDPOINT: &str = "http://169.254.169.254";
+const IMDS_TOKEN_TTL_SECONDS: u32 = 21600;
+
+/// Credentials of the first provider which has them:
+/// - env vars `AWS_ACCESS_KEY_ID`, `AWS_SECRET_ACCESS_KEY`, `AWS_SESSION_TOKEN` (also set for Lambda execution role)
+/// - profile `AWS_PROFILE` (or `default`) of `~/.aws/credentials`
+/// - container credentials endpoint `AWS_CONTAINER_CREDENTIALS_RELATIVE_URI`/`AWS_CONTAINER_CREDENTIALS_FULL_URI`
+/// - EC2 instance IAM role via IMDSv2, see `InstanceMetadataProvider`
+#[derive(Clone, Debug)]
+pub struct ChainProvider {
+    environment: EnvironmentProvider,
+    profile: Option<ProfileProvider>,
+    container: ContainerProvider,
+    instance_metadata: InstanceMetadataProvider,
+}
+
+impl ChainProvider {
+    pub fn new() -> Self {
+        Self {
+            environment: EnvironmentProvider::default(),
+            profile: ProfileProvider::new().ok(),
+            container: ContainerProvider::new(),
+            instance_metadata: InstanceMetadataProvider::new(),
+        }
+    }
+    pub fn instance_metadata(self, instance_metadata: InstanceMetadataProvider) -> Self {
+        Self {
+            instance_metadata,
+            ..self
+        }
+    }
+    /// Caches credentials until they expire
+    pub fn auto_refreshing(self) -> Result<AutoRefreshingProvider<Self>> {
+        AutoRefreshingProvider::new(self).map_err(|err| anyhow!(err))
+    }
+}
+
+impl Default for ChainProvider {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[async_trait]
+impl ProvideAwsCredentials for ChainProvider {
+    async fn credentials(&self) -> Result<AwsCredentials, CredentialsError> {
+        let mut errors = vec![];
+        match self.environment.credentials().await {
+            Ok(credentials) => return Ok(credentials),
+            Err(err) => errors.push(format!("env: {err}")),
+        }
+        if let Some(profile) = self.profile.as_ref() {
+            match profile.credentials().await {
+                Ok(credentials) => return Ok(credentials),
+                Err(err) => errors.push(format!("profile: {err}")),
+            }
+        }
+        match self.container.credentials().await {
+            Ok(credentials) => return Ok(credentials),
+            Err(err) => errors.push(format!("container: {err}")),
+        }
+        match self.instance_metadata.credentials().await {
+            Ok(credentials) => return Ok(credentials),
+            Err(err) => errors.push(format!("instance metadata: {err}")),
+        }
+        Err(CredentialsError::new(format!(
+            "no AWS credentials found: {}",
+            errors.join("; ")
+        )))
+    }
+}
+
+/// Credentials of EC2 instance IAM role from instance metadata service: IMDSv2 (session token),
+/// IMDSv1 if token is not available.
+/// Endpoint is `AWS_EC2_METADATA_SERVICE_ENDPOINT` if set (e.g. local mock), disabled by `AWS_EC2_METADATA_DISABLED=true`
+#[derive(Clone, Debug)]
+pub struct InstanceMetadataProvider {
+    endpoint: String,
+    timeout: Duration,
+    disabled: bool,
+}
+
+impl InstanceMetadataProvider {
+    pub fn new() -> Self {
+        Self {
+            endpoint: std::env::var("AWS_EC2_METADATA_SERVICE_ENDPOINT")
+                .unwrap_or_else(|_| IMDS_ENDPOINT.to_owned()),
+            timeout: Duration::from_secs(1),
+            disabled: std::env::var("AWS_EC2_METADATA_DISABLED")
+                .map(|s| s.eq_ignore_ascii_case("true"))
+                .unwrap_or(false),
+        }
+    }
+    pub fn endpoint(self, endpoint: String) -> Self {
+        Self {
+            endpoint,
+            disabled: false,
+            ..self
+        }
+    }
+    pub fn timeout(self, timeout: Duration) -> Self {
+        Self { timeout, ..self }
+    }
+
+    async fn request(
+        &self,
+        method: hyper::Method,
+        path: &str,
+        headers: &[(&str, String)],
+    ) -> Result<(hyper::StatusCode, String)> {
+        let uri = format!("{}{path}", self.endpoint.trim_end_matches('/'));
+        let mut request = hyper::Request::builder().method(method).uri(&uri);
+        for (name, value) in headers {
+            request = request.header(*name, value);
+        }
+        let request = request.body(hyper::Body::empty())?;
+        let client = hyper::Client::new();
+        let response = tokio::time::timeout(self.timeout, client.request(request))
+            .await
+            .map_err(|_| anyhow!("{uri}: timed out"))?
+            .map_err(|err| anyhow!("{uri}: {err}"))?;
+        let status = response.status();
+        let body = tokio::time::timeout(self.timeout, hyper::body::to_bytes(response.into_body()))
+            .await
+            .map_err(|_| anyhow!("{uri}: timed out"))?
+            .map_err(|err| anyhow!("{uri}: {err}"))?;
+        Ok((status, String::from_utf8_lossy(&body).into_owned()))
+    }
+
+    async fn get(&self, path: &str, token: Option<&str>) -> Result<String> {
+        let headers = token
+            .map(|token| vec![("x-aws-ec2-metadata-token", token.to_owned())])
+            .unwrap_or_default();
+        match self.request(hyper::Method::GET, path, &headers).await? {
+            (status, body) if status.is_success() => Ok(body),
+            (status, _) => bail!("{path}: {status}"),
+        }
+    }
+
+    /// `None` if IMDSv2 is not available
+    async fn token(&self) -> Result<Option<String>> {
+        let headers = [(
+            "x-aws-ec2-metadata-token-ttl-seconds",
+            IMDS_TOKEN_TTL_SECONDS.to_string(),
+        )];
+        match self
+            .request(hyper::Method::PUT, "/latest/api/token", &headers)
+            .await?
+        {
+            (status, body) if status.is_success() => Ok(Some(body)),
+            (status, _)
+                if status == hyper::StatusCode::FORBIDDEN
+                    || status == hyper::StatusCode::NOT_FOUND
+                    || status == hyper::StatusCode::METHOD_NOT_ALLOWED =>
+            {
+                debug!("IMDSv2 token is not available ({status}), will use IMDSv1");
+                Ok(None)
+            }
+            (status, _) => bail!("/latest/api/token: {status}"),
+        }
+    }
+
+    async fn fetch(&self) -> Result<AwsCredentials> {
+        #[derive(serde::Deserialize)]
+        #[serde(rename_all = "PascalCase")]
+        struct RoleCredentials {
+            access_key_id: String,
+            secret_access_key: String,
+            token: Option<String>,
+            expiration: Option<chrono::DateTime<chrono::Utc>>,
+        }
+        const PATH: &str = "/latest/meta-data/iam/security-credentials/";
+        let token = self.token().await?;
+        let role = self.get(PATH, token.as_deref()).await?;
+        let role = role
+            .lines()
+            .next()
+            .map(|s| s.trim())
+            .filter(|s| !s.is_empty())
+            .ok_or_else(|| anyhow!("no IAM role is attached to instance"))?;
+        let body = self.get(&format!("{PATH}{role}"), token.as_deref()).await?;
+        let credentials = serde_json::from_str::<RoleCredentials>(&body)
+            .map_err(|err| anyhow!("failed to parse credentials of role {role:?}: {err}"))?;
+        Ok(AwsCredentials::new(
+            credentials.access_key_id,
+            credentials.secret_access_key,
+            credentials.token,
+            credentials.expiration,
+        ))
+    }
+}
+
+impl Default for InstanceMetadataProvider {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[async_trait]
+impl ProvideAwsCredentials for InstanceMetadataProvider {
+    async fn credentials(&self) -> Result<AwsCredentials, CredentialsError> {
+        if self.disabled {
+            return Err(CredentialsError::new(
+                "disabled by AWS_EC2_METADATA_DISABLED",
+            ));
+        }
+        self.fetch()
+            .await
+            .map_err(|err| CredentialsError::new(format!("{err:#}")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    const TOKEN: &str = "mock-token";
+
+    /// Minimal instance metadata service; requires token if `v2_only`, does not issue it if `v1_only`
+    async fn mock_metadata_server(v2_only: bool, v1_only: bool) -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let endpoint = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            loop {
+                let Ok((mut stream, _)) = listener.accept().await else {
+                    break;
+                };
+                // reads request up to the end of its head (requests have no body), so closing stream does not
+                // reset it
+                let mut request = vec![];
+                let mut buf = [0u8; 4096];
+                while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+                    match stream.read(&mut buf).await {
+                        Ok(n @ 1..) => request.extend_from_slice(&buf[..n]),
+                        _ => break,
+                    }
+                }
+                let request = String::from_utf8_lossy(&request).to_lowercase();
+                let has_token = request.contains(&format!("x-aws-ec2-metadata-token: {TOKEN}"));
+                let (status, body) = if request.starts_with("put /latest/api/token ") {
+                    if v1_only {
+                        ("403 Forbidden", String::new())
+                    } else {
+                        ("200 OK", TOKEN.to_owned())
+                    }
+                } else if v2_only && !has_token {
+                    ("401 Unauthorized", String::new())
+                } else if request.starts_with("get /latest/meta-data/iam/security-credentials/ ") {
+                    ("200 OK", "backup-role\n".to_owned())
+                } else if request
+                    .starts_with("get /latest/meta-data/iam/security-credentials/backup-role ")
+                {
+                    (
+                        "200 OK",
+                        r#"{
+                            "Code": "Success",
+                            "AccessKeyId": "AKIDMOCK",
+                            "SecretAccessKey": "secret",
+                            "Token": "session",
+                            "Expiration": "2100-01-01T00:00:00Z"
+                        }"#
+                        .to_owned(),
+                    )
+                } else {
+                    ("404 Not Found", String::new())
+                };
+                let response = format!(
+                    "HTTP/1.1 {status}\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{body}",
+                    body.len()
+                );
+                let _ = stream.write_all(response.as_bytes()).await;
+            }
+        });
+        endpoint
+    }
+
+    #[tokio::test]
+    async fn test_imdsv2() {
+        let endpoint = mock_metadata_server(true, false).await;
+        let credentials = InstanceMetadataProvider::new()
+            .endpoint(endpoint)
+            .credentials()
+            .await
+            .unwrap();
+        assert_eq!(credentials.aws_access_key_id(), "AKIDMOCK");
+        assert_eq!(credentials.aws_secret_access_key(), "secret");
+        assert_eq!(credentials.token().as_deref(), Some("session"));
+        assert!(credentials.expires_at().is_some());
+    }
+
+    #[tokio::test]
+    async fn test_imdsv1_fallback() {
+        let endpoint = mock_metadata_server(false, true).await;
+        let credentials = InstanceMetadataProvider::new()
+            .endpoint(endpoint)
+            .credentials()
+            .await
+            .unwrap();
+        assert_eq!(credentials.aws_access_key_id(), "AKIDMOCK");
+    }
+
+    #[tokio::test]
+    async fn test_imds_unreachable() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoint = format!("http://{}", listener.local_addr().unwrap());
+        drop(listener);
+        assert!(InstanceMetadataProvider::new()
+            .endpoint(endpoint)
+            .credentials()
+            .await
+            .is_err());
+    }
+}
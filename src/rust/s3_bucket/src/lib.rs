@@ -10,9 +10,19 @@ pub use rusoto_core::{request::HttpClient, ByteStream, RusotoError};
 pub use rusoto_core::credential::StaticProvider;
 pub use rusoto_core::Region;
 
+pub mod addressing;
+pub mod credentials;
+pub mod multipart;
+pub mod retry;
+pub use addressing::Addressing;
+pub use credentials::{ChainProvider, InstanceMetadataProvider};
+pub use multipart::{MultipartArg, MultipartWriter};
+pub use retry::upload_retries;
+
 use rusoto_s3::{
     DeleteObjectRequest,
     GetObjectRequest,
+    HeadBucketRequest,
     HeadObjectRequest,
     // ListObjectsV2Output,
     ListObjectsV2Request,
@@ -36,10 +46,32 @@ pub struct HeadResponse {
     pub last_modified: Option<chrono::DateTime<chrono::Utc>>,
 }
 
+/// Server-side encryption requested for uploaded objects
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ServerSideEncryption {
+    /// SSE-S3, `x-amz-server-side-encryption: AES256`
+    Aes256,
+    /// SSE-KMS, `x-amz-server-side-encryption: aws:kms` with key of `sse_kms_key_id` (default KMS key of bucket if not set)
+    AwsKms,
+}
+
+impl ServerSideEncryption {
+    fn header(&self) -> &'static str {
+        match self {
+            Self::Aes256 => "AES256",
+            Self::AwsKms => "aws:kms",
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct S3Bucket {
     client: S3Client,
     bucket: String,
+    sse: Option<ServerSideEncryption>,
+    sse_kms_key_id: Option<String>,
+    retries: u32,
     // fetch_limit: usize,
     // max_attempt: usize,
 }
@@ -47,6 +79,11 @@ pub struct S3Bucket {
 pub struct S3BucketBuilder {
     provider: Option<StaticProvider>,
     region: Option<Region>,
+    addressing: Addressing,
+    dual_stack: bool,
+    sse: Option<ServerSideEncryption>,
+    sse_kms_key_id: Option<String>,
+    retries: u32,
     bucket: String,
 }
 
@@ -56,31 +93,90 @@ impl S3BucketBuilder {
             bucket,
             provider: None,
             region: None,
+            addressing: Addressing::Path,
+            dual_stack: false,
+            sse: None,
+            sse_kms_key_id: None,
+            retries: retry::DEFAULT_RETRIES,
         }
     }
     pub fn region(self, region: Region) -> Self {
         Self {
-            bucket: self.bucket,
             region: Some(region),
-            provider: self.provider,
+            ..self
         }
     }
     pub fn provider(self, provider: StaticProvider) -> Self {
         Self {
-            bucket: self.bucket,
-            region: self.region,
             provider: Some(provider),
+            ..self
+        }
+    }
+    pub fn addressing(self, addressing: Addressing) -> Self {
+        Self { addressing, ..self }
+    }
+    /// Use dual-stack (IPv4 and IPv6) endpoint `s3.dualstack.${region}.amazonaws.com` of AWS region
+    pub fn dual_stack(self, dual_stack: bool) -> Self {
+        Self { dual_stack, ..self }
+    }
+    /// Server-side encryption of objects uploaded by `upload` and `multipart_writer`
+    pub fn server_side_encryption(self, sse: Option<ServerSideEncryption>) -> Self {
+        Self { sse, ..self }
+    }
+    /// KMS key of `ServerSideEncryption::AwsKms`
+    pub fn sse_kms_key_id(self, sse_kms_key_id: Option<String>) -> Self {
+        Self {
+            sse_kms_key_id,
+            ..self
         }
     }
+    /// How many times upload (`S3Bucket::upload` from bytes, part of `MultipartWriter`) is retried on network error, 5xx
+    /// or 429 before it fails
+    pub fn retries(self, retries: u32) -> Self {
+        Self { retries, ..self }
+    }
+    /// Uses `ChainProvider` if `provider` is not set
     pub fn build(self) -> Result<S3Bucket> {
         let request_dispatcher = HttpClient::new()?;
-        let provider = match self.provider {
-            Some(provider) => provider,
-            None => Self::default_provider()?,
-        };
         let region = self.region.unwrap_or_else(Self::default_region);
-        let client = S3Client::new_with(request_dispatcher, provider, region);
-        Ok(S3Bucket::new(client, self.bucket))
+        let region = if self.dual_stack {
+            Self::dual_stack_region(region)?
+        } else {
+            region
+        };
+        let client = match (self.provider, self.addressing) {
+            (Some(provider), Addressing::Path) => {
+                S3Client::new_with(request_dispatcher, provider, region)
+            }
+            (None, Addressing::Path) => {
+                S3Client::new_with(request_dispatcher, Self::default_provider()?, region)
+            }
+            (Some(provider), Addressing::VirtualHost) => S3Client::new_with_client(
+                rusoto_core::Client::new_not_signing(addressing::VirtualHostDispatcher::new(
+                    request_dispatcher,
+                    provider,
+                    self.bucket.clone(),
+                )),
+                region,
+            ),
+            (None, Addressing::VirtualHost) => S3Client::new_with_client(
+                rusoto_core::Client::new_not_signing(addressing::VirtualHostDispatcher::new(
+                    request_dispatcher,
+                    Self::default_provider()?,
+                    self.bucket.clone(),
+                )),
+                region,
+            ),
+        };
+        let sse = self.sse;
+        Ok(S3Bucket {
+            sse,
+            sse_kms_key_id: self
+                .sse_kms_key_id
+                .filter(|_| sse == Some(ServerSideEncryption::AwsKms)),
+            retries: self.retries,
+            ..S3Bucket::new(client, self.bucket)
+        })
     }
     fn default_region() -> Region {
         let name = std::env::var("S3_REGION_NAME").unwrap_or_else(|_| "us-east-1".to_owned());
@@ -89,16 +185,20 @@ impl S3BucketBuilder {
 
         Region::Custom { name, endpoint }
     }
-    fn default_provider() -> Result<StaticProvider> {
-        todo!();
-        // let_from_env!(access_key, S3_ACCESS_KEY);
-        // let_from_env!(secret_access_key, S3_SECRET_ACCESS_KEY);
-        // Ok(StaticProvider::new(
-        //     access_key,
-        //     secret_access_key,
-        //     None,
-        //     None,
-        // ))
+    fn dual_stack_region(region: Region) -> Result<Region> {
+        match region {
+            Region::Custom { name, endpoint } => bail!(
+                "dual-stack endpoint is known for AWS regions only, got custom region {name:?} with endpoint {endpoint:?}"
+            ),
+            region => Ok(Region::Custom {
+                endpoint: format!("https://s3.dualstack.{}.amazonaws.com", region.name()),
+                name: region.name().to_owned(),
+            }),
+        }
+    }
+    fn default_provider(
+    ) -> Result<rusoto_core::credential::AutoRefreshingProvider<credentials::ChainProvider>> {
+        credentials::ChainProvider::new().auto_refreshing()
     }
 }
 
@@ -107,10 +207,35 @@ impl S3Bucket {
         Self {
             client,
             bucket,
+            sse: None,
+            sse_kms_key_id: None,
+            retries: retry::DEFAULT_RETRIES,
             // fetch_limit: config.content.s3.fetch_limit,
             // max_attempt: config.content.s3.max_attempt,
         }
     }
+    pub fn bucket(&self) -> &str {
+        &self.bucket
+    }
+    /// Checks that bucket exists and credentials give access to it (HeadBucket)
+    pub async fn check(&self) -> Result<()> {
+        let req = HeadBucketRequest {
+            bucket: self.bucket.clone(),
+            ..Default::default()
+        };
+        match self.client.head_bucket(req).await {
+            Ok(()) => Ok(()),
+            Err(RusotoError::Unknown(resp)) => match resp.status.as_u16() {
+                403 => bail!(
+                    "access to bucket {:?} is denied, check credentials",
+                    self.bucket
+                ),
+                404 => bail!("bucket {:?} does not exist", self.bucket),
+                status => bail!("bucket {:?}: HTTP {status}", self.bucket),
+            },
+            Err(err) => bail!("bucket {:?}: {err}", self.bucket),
+        }
+    }
     pub async fn head(&self, key: String) -> Result<Option<HeadResponse>> {
         let key_clone = key.to_owned();
         // https://rusoto.github.io/rusoto/rusoto_s3/struct.HeadObjectRequest.html
@@ -207,17 +332,35 @@ impl S3Bucket {
         let resp = self.client.get_object(req).await?;
         Ok(resp.body)
     }
+    /// Retries upload (see `S3BucketBuilder::retries`) if `object_to_upload` is built from bytes, which can be sent
+    /// again
     pub async fn upload(&self, key: String, object_to_upload: ObjectToUpload) -> Result<()> {
+        let ObjectToUpload {
+            body,
+            bytes,
+            content_type,
+            content_length,
+        } = object_to_upload;
         // https://rusoto.github.io/rusoto/rusoto_s3/struct.PutObjectRequest.html
-        let req = PutObjectRequest {
-            body: Some(object_to_upload.body),
-            content_length: object_to_upload.content_length,
-            content_type: object_to_upload.content_type,
+        let req = |body| PutObjectRequest {
+            body: Some(body),
+            content_length,
+            content_type: content_type.clone(),
             bucket: self.bucket.clone(),
-            key,
+            key: key.clone(),
+            server_side_encryption: self.sse.map(|sse| sse.header().to_owned()),
+            ssekms_key_id: self.sse_kms_key_id.clone(),
             ..Default::default()
         };
-        let _ = self.client.put_object(req).await?;
+        match bytes {
+            Some(bytes) => {
+                retry::upload(&format!("{key:?}"), self.retries, || {
+                    self.client.put_object(req(bytes_stream(bytes.clone())))
+                })
+                .await?
+            }
+            None => self.client.put_object(req(body)).await?,
+        };
         Ok(())
     }
     pub async fn list(&self, arg: ListArg) -> Result<ListRet> {
@@ -246,14 +389,10 @@ impl S3Bucket {
                 };
                 list_items.push(list_item);
             }
-            if resp.is_truncated.is_some()
-                && resp.is_truncated.unwrap()
-                && resp.next_continuation_token.is_some()
+            if let (Some(true), Some(next_continuation_token)) =
+                (resp.is_truncated, resp.next_continuation_token)
             {
-                Ok(ListRet::ToBeContinue(
-                    resp.next_continuation_token.unwrap(),
-                    list_items,
-                ))
+                Ok(ListRet::ToBeContinue(next_continuation_token, list_items))
             } else if !list_items.is_empty() {
                 Ok(ListRet::Finished(Some(list_items)))
             } else {
@@ -344,6 +483,7 @@ list_limit_try_from!(u => u64);
 
 pub struct ObjectToUploadBuilder {
     body: ByteStream,
+    bytes: Option<Bytes>,
     content_type: Option<String>,
     content_length: Option<i64>,
 }
@@ -358,34 +498,35 @@ impl ObjectToUploadBuilder {
         Self::from_bytes(body)
     }
     pub fn from_bytes(body: Bytes) -> Self {
-        let stream = stream::once(async { Ok(body) });
-        let body = ByteStream::new(stream);
-        Self::from_bytestream(body)
+        Self {
+            bytes: Some(body.clone()),
+            ..Self::from_bytestream(bytes_stream(body))
+        }
     }
     pub fn from_bytestream(body: ByteStream) -> Self {
         Self {
             body,
+            bytes: None,
             content_length: None,
             content_type: None,
         }
     }
     pub fn content_length(self, content_length: Option<i64>) -> Self {
         Self {
-            body: self.body,
-            content_type: self.content_type,
             content_length,
+            ..self
         }
     }
     pub fn content_type(self, content_type: Option<String>) -> Self {
         Self {
-            body: self.body,
             content_type,
-            content_length: self.content_length,
+            ..self
         }
     }
     pub fn build(self) -> ObjectToUpload {
         ObjectToUpload {
             body: self.body,
+            bytes: self.bytes,
             content_type: self.content_type,
             content_length: self.content_length,
         }
@@ -393,10 +534,16 @@ impl ObjectToUploadBuilder {
 }
 pub struct ObjectToUpload {
     pub body: ByteStream,
+    /// Body if it is built from bytes, to be sent again on retry
+    bytes: Option<Bytes>,
     pub content_type: Option<String>,
     pub content_length: Option<i64>,
 }
 
+fn bytes_stream(bytes: Bytes) -> ByteStream {
+    ByteStream::new(stream::once(async { Ok(bytes) }))
+}
+
 // https://stackoverflow.com/questions/59318460/what-is-the-best-way-to-convert-an-asyncread-to-a-trystream-of-bytes/59327560#59327560
 
 pub fn into_byte_stream<R>(r: R) -> impl Stream<Item = tokio::io::Result<u8>>
@@ -426,7 +573,28 @@ mod tests {
     use tokio::io::AsyncReadExt;
 
     use std::convert::TryInto;
+
+    /// `let $var = env::var("$ENV")?` (parsed as `$type` if set), as `common_macros::let_from_env!` did
+    macro_rules! let_from_env {
+        ($var:ident, $env:ident) => {
+            let $var = std::env::var(stringify!($env))
+                .context(format!("{} required", stringify!($env)))?;
+        };
+        ($var:ident, $env:ident, $type:ty) => {
+            let_from_env!($var, $env);
+            let $var = $var.parse::<$type>().context(format!(
+                "{}: failed to parse {:?}",
+                stringify!($env),
+                $var
+            ))?;
+        };
+    }
+
+    /// Content type uploaded and expected back by upload tests
+    const CONTENT_TYPE: &str = "application/octet-stream";
+
     #[tokio::test]
+    #[ignore = "requires S3_BUCKET and credentials in .env"]
     async fn test_list() -> Result<()> {
         dotenv()?;
         let _ = pretty_env_logger::try_init_timed();
@@ -460,6 +628,7 @@ mod tests {
     }
 
     #[tokio::test]
+    #[ignore = "requires S3_BUCKET and credentials in .env"]
     async fn test_upload_file() -> Result<()> {
         dotenv().context("file .env")?;
         let _ = pretty_env_logger::try_init_timed();
@@ -472,13 +641,11 @@ mod tests {
             let key = filepath.file_name().unwrap().to_string_lossy().to_string();
             while s3b.head(key.clone()).await?.is_some() {
                 s3b.delete(key.clone()).await?;
-                tokio::time::delay_for(tokio::time::Duration::from_millis(500)).await;
+                tokio::time::sleep(tokio::time::Duration::from_millis(500)).await;
             }
-            let mut file = tokio::fs::File::open(filepath).await?;
-            let mime_sniff::Ret {
-                content_type,
-                content_length,
-            } = mime_sniff::tokio_file(&mut file).await?;
+            let file = tokio::fs::File::open(filepath).await?;
+            let content_type = Some(CONTENT_TYPE.to_owned());
+            let content_length = Some(file.metadata().await?.len() as i64);
 
             info!(
                 "filepath: {:?}, content_type: {:?}, content_length: {:?}",
@@ -492,7 +659,7 @@ mod tests {
             let head = s3b
                 .head(key.clone())
                 .await?
-                .expect(&format!("key {} must exist", key));
+                .unwrap_or_else(|| panic!("key {} must exist", key));
             assert_eq!(head.content_type, content_type);
             assert_eq!(head.content_length, content_length);
         }
@@ -501,6 +668,7 @@ mod tests {
     }
 
     #[tokio::test]
+    #[ignore = "requires S3_BUCKET and credentials in .env"]
     async fn test_upload_vecu8() -> Result<()> {
         dotenv()?;
         let _ = pretty_env_logger::try_init_timed();
@@ -514,17 +682,15 @@ mod tests {
             // while s3b.exists(key.clone()).await? {
             while s3b.head(key.clone()).await?.is_some() {
                 s3b.delete(key.clone()).await?;
-                tokio::time::delay_for(tokio::time::Duration::from_millis(500)).await;
+                tokio::time::sleep(tokio::time::Duration::from_millis(500)).await;
             }
             let mut file = tokio::fs::File::open(filepath).await?;
 
             let mut buffer = vec![];
             file.read_to_end(&mut buffer).await?;
 
-            let mime_sniff::Ret {
-                content_type,
-                content_length,
-            } = mime_sniff::slice(&buffer);
+            let content_type = Some(CONTENT_TYPE.to_owned());
+            let content_length = Some(buffer.len() as i64);
 
             info!(
                 "filepath: {:?}, content_type: {:?}, content_length: {:?}",
@@ -538,7 +704,7 @@ mod tests {
             let head = s3b
                 .head(key.clone())
                 .await?
-                .expect(&format!("key {} must exist", key));
+                .unwrap_or_else(|| panic!("key {} must exist", key));
             assert_eq!(head.content_type, content_type);
             assert_eq!(head.content_length, content_length);
         }
@@ -546,6 +712,7 @@ mod tests {
     }
 
     #[tokio::test]
+    #[ignore = "requires S3_BUCKET and credentials in .env"]
     async fn test_list_photo() -> Result<()> {
         dotenv()?;
         let _ = pretty_env_logger::try_init_timed();
@@ -596,4 +763,34 @@ mod tests {
         );
         Ok(())
     }
+
+    #[tokio::test]
+    #[ignore = "requires S3_BUCKET and credentials in .env"]
+    async fn test_multipart_writer() -> Result<()> {
+        use tokio::io::AsyncWriteExt;
+        dotenv().context("file .env")?;
+        let _ = pretty_env_logger::try_init_timed();
+        let_from_env!(bucket, S3_BUCKET);
+        let s3b = S3BucketBuilder::new(bucket).build()?;
+        let key = "test_multipart_writer.bin".to_owned();
+        let mut writer = s3b
+            .multipart_writer(
+                key.clone(),
+                MultipartArg::new()
+                    .part_size(multipart::MIN_PART_SIZE)
+                    .concurrency(2),
+            )
+            .await?;
+        let len = multipart::MIN_PART_SIZE * 2 + 1024;
+        let data = (0..len).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        for chunk in data.chunks(100_000) {
+            writer.write_all(chunk).await?;
+        }
+        writer.shutdown().await?;
+        assert_eq!(writer.uploaded(), Some(len as u64));
+        let head = s3b.head(key.clone()).await?.context("uploaded object")?;
+        assert_eq!(head.content_length, Some(len as i64));
+        s3b.delete(key).await?;
+        Ok(())
+    }
 }
@@ -0,0 +1,316 @@
+#[allow(unused_imports)]
+use anyhow::{anyhow, bail, Context, Error, Result};
+
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+
+use super::{retry, S3Bucket};
+use bytes::Bytes;
+use futures::{StreamExt, TryStreamExt};
+use rusoto_s3::{
+    AbortMultipartUploadRequest, CompleteMultipartUploadRequest, CompletedMultipartUpload,
+    CompletedPart, CreateMultipartUploadRequest, UploadPartRequest, S3,
+};
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{ready, Context as TaskContext, Poll};
+use tokio::io::AsyncWrite;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio_util::sync::PollSender;
+
+/// S3 does not accept parts (but the last one) less than 5 MiB
+pub const MIN_PART_SIZE: usize = 5 * 1024 * 1024;
+
+pub struct MultipartArg {
+    part_size: usize,
+    concurrency: usize,
+    content_type: Option<String>,
+}
+
+impl Default for MultipartArg {
+    fn default() -> Self {
+        Self {
+            part_size: 8 * 1024 * 1024,
+            concurrency: 4,
+            content_type: None,
+        }
+    }
+}
+
+impl MultipartArg {
+    pub fn new() -> Self {
+        Default::default()
+    }
+    /// Raised to `MIN_PART_SIZE` if less
+    pub fn part_size(self, part_size: usize) -> Self {
+        Self {
+            part_size: part_size.max(MIN_PART_SIZE),
+            ..self
+        }
+    }
+    /// How many parts are uploaded at once, at least 1
+    pub fn concurrency(self, concurrency: usize) -> Self {
+        Self {
+            concurrency: concurrency.max(1),
+            ..self
+        }
+    }
+    pub fn content_type(self, content_type: Option<String>) -> Self {
+        Self {
+            content_type,
+            ..self
+        }
+    }
+}
+
+enum Message {
+    Part(i64, Bytes),
+    /// All parts are sent; upload is aborted if channel is closed without it
+    Finish,
+}
+
+/// `AsyncWrite` sink of multipart upload: bytes written are cut into parts of `part_size` which are uploaded
+/// in background, `concurrency` at once; `shutdown()` uploads the last part and completes upload, returns error
+/// if any part failed. Upload is aborted if any part fails or writer is dropped before `shutdown()`.
+/// Memory is bounded by about `(2 * concurrency + 1) * part_size`
+pub struct MultipartWriter {
+    key: String,
+    part_size: usize,
+    buf: Vec<u8>,
+    part_number: i64,
+    tx: PollSender<Message>,
+    is_finish_sent: bool,
+    uploader: JoinHandle<Result<u64>>,
+    /// Set by `shutdown()`: total bytes uploaded
+    uploaded: Option<u64>,
+}
+
+impl S3Bucket {
+    /// Starts multipart upload of `key`
+    pub async fn multipart_writer(
+        &self,
+        key: String,
+        arg: MultipartArg,
+    ) -> Result<MultipartWriter> {
+        let req = CreateMultipartUploadRequest {
+            bucket: self.bucket.clone(),
+            key: key.clone(),
+            content_type: arg.content_type,
+            server_side_encryption: self.sse.map(|sse| sse.header().to_owned()),
+            ssekms_key_id: self.sse_kms_key_id.clone(),
+            ..Default::default()
+        };
+        let upload_id = self
+            .client
+            .create_multipart_upload(req)
+            .await
+            .map_err(|err| anyhow!("failed to create multipart upload of {key:?}: {err}"))?
+            .upload_id
+            .ok_or_else(|| anyhow!("no upload_id for multipart upload of {key:?}"))?;
+        let (tx, rx) = mpsc::channel(arg.concurrency);
+        let uploader = tokio::spawn(upload_parts(
+            self.clone(),
+            key.clone(),
+            upload_id,
+            arg.concurrency,
+            rx,
+        ));
+        Ok(MultipartWriter {
+            key,
+            part_size: arg.part_size,
+            buf: Vec::with_capacity(arg.part_size),
+            part_number: 1,
+            tx: PollSender::new(tx),
+            is_finish_sent: false,
+            uploader,
+            uploaded: None,
+        })
+    }
+}
+
+impl MultipartWriter {
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+    /// Total bytes uploaded, `Some` after successful `shutdown()`
+    pub fn uploaded(&self) -> Option<u64> {
+        self.uploaded
+    }
+    fn poll_send(
+        &mut self,
+        cx: &mut TaskContext<'_>,
+        message: Message,
+    ) -> Poll<std::io::Result<()>> {
+        ready!(self.tx.poll_reserve(cx)).map_err(|_| self.gone())?;
+        self.tx.send_item(message).map_err(|_| self.gone())?;
+        Poll::Ready(Ok(()))
+    }
+    fn poll_send_part(&mut self, cx: &mut TaskContext<'_>) -> Poll<std::io::Result<()>> {
+        ready!(self.tx.poll_reserve(cx)).map_err(|_| self.gone())?;
+        let part = Bytes::from(std::mem::replace(
+            &mut self.buf,
+            Vec::with_capacity(self.part_size),
+        ));
+        self.tx
+            .send_item(Message::Part(self.part_number, part))
+            .map_err(|_| self.gone())?;
+        self.part_number += 1;
+        Poll::Ready(Ok(()))
+    }
+    fn gone(&self) -> std::io::Error {
+        std::io::Error::other(format!(
+            "multipart upload of {:?} failed, see shutdown() for details",
+            self.key
+        ))
+    }
+}
+
+impl AsyncWrite for MultipartWriter {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        data: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+        if this.buf.len() >= this.part_size {
+            ready!(this.poll_send_part(cx))?;
+        }
+        let len = data.len().min(this.part_size - this.buf.len());
+        this.buf.extend_from_slice(&data[..len]);
+        Poll::Ready(Ok(len))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut TaskContext<'_>) -> Poll<std::io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        if this.uploaded.is_some() {
+            return Poll::Ready(Ok(()));
+        }
+        if !this.is_finish_sent {
+            // the last part may be less than `MIN_PART_SIZE`, upload of no bytes is a single empty part
+            if !this.buf.is_empty() || this.part_number == 1 {
+                ready!(this.poll_send_part(cx))?;
+            }
+            ready!(this.poll_send(cx, Message::Finish))?;
+            this.is_finish_sent = true;
+            this.tx.close();
+        }
+        let uploaded = ready!(Pin::new(&mut this.uploader).poll(cx))
+            .map_err(std::io::Error::other)?
+            .map_err(|err| std::io::Error::other(format!("{err:#}")))?;
+        this.uploaded = Some(uploaded);
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Uploads parts received from `rx`, completes upload on `Message::Finish`, aborts it on failure
+async fn upload_parts(
+    s3b: S3Bucket,
+    key: String,
+    upload_id: String,
+    concurrency: usize,
+    mut rx: mpsc::Receiver<Message>,
+) -> Result<u64> {
+    let is_finished = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
+    // stops at the first failed part: `rx` is dropped, so writer fails too
+    let parts = {
+        let is_finished = is_finished.clone();
+        futures::stream::poll_fn(move |cx| rx.poll_recv(cx))
+            .filter_map(move |message| {
+                let ret = match message {
+                    Message::Part(part_number, body) => Some((part_number, body)),
+                    Message::Finish => {
+                        is_finished.store(true, std::sync::atomic::Ordering::SeqCst);
+                        None
+                    }
+                };
+                futures::future::ready(ret)
+            })
+            .map(|(part_number, body)| upload_part(&s3b, &key, &upload_id, part_number, body))
+            .buffer_unordered(concurrency)
+            .try_collect::<Vec<_>>()
+            .await
+    };
+    let parts = match parts {
+        Ok(_) if !is_finished.load(std::sync::atomic::Ordering::SeqCst) => {
+            abort(&s3b, &key, &upload_id).await;
+            bail!("writer of {key:?} is dropped before shutdown()");
+        }
+        Ok(parts) => parts,
+        Err(err) => {
+            abort(&s3b, &key, &upload_id).await;
+            return Err(err);
+        }
+    };
+    let (mut completed, lens): (Vec<_>, Vec<_>) = parts.into_iter().unzip();
+    let uploaded = lens.into_iter().sum();
+    completed.sort_by_key(|part| part.part_number);
+    let req = CompleteMultipartUploadRequest {
+        bucket: s3b.bucket.clone(),
+        key: key.clone(),
+        upload_id: upload_id.clone(),
+        multipart_upload: Some(CompletedMultipartUpload {
+            parts: Some(completed),
+        }),
+        ..Default::default()
+    };
+    if let Err(err) = s3b.client.complete_multipart_upload(req).await {
+        abort(&s3b, &key, &upload_id).await;
+        bail!("failed to complete multipart upload of {key:?}: {err}");
+    }
+    Ok(uploaded)
+}
+
+async fn upload_part(
+    s3b: &S3Bucket,
+    key: &str,
+    upload_id: &str,
+    part_number: i64,
+    body: Bytes,
+) -> Result<(CompletedPart, u64)> {
+    let len = body.len();
+    let what = format!("part {part_number} of {key:?}");
+    let e_tag = retry::upload(&what, s3b.retries, || {
+        let req = UploadPartRequest {
+            bucket: s3b.bucket.clone(),
+            key: key.to_owned(),
+            upload_id: upload_id.to_owned(),
+            part_number,
+            content_length: Some(len as i64),
+            body: Some(rusoto_core::ByteStream::new_with_size(
+                futures::stream::once(futures::future::ready(Ok(body.clone()))),
+                len,
+            )),
+            ..Default::default()
+        };
+        s3b.client.upload_part(req)
+    })
+    .await
+    .map_err(|err| anyhow!("failed to upload {what}: {err}"))?
+    .e_tag;
+    debug!("did upload {what}: {len} bytes");
+    Ok((
+        CompletedPart {
+            e_tag,
+            part_number: Some(part_number),
+        },
+        len as u64,
+    ))
+}
+
+async fn abort(s3b: &S3Bucket, key: &str, upload_id: &str) {
+    let req = AbortMultipartUploadRequest {
+        bucket: s3b.bucket.clone(),
+        key: key.to_owned(),
+        upload_id: upload_id.to_owned(),
+        ..Default::default()
+    };
+    match s3b.client.abort_multipart_upload(req).await {
+        Ok(_) => warn!("did abort multipart upload of {key:?}"),
+        Err(err) => error!("failed to abort multipart upload of {key:?}: {err}"),
+    }
+}
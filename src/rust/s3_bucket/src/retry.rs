@@ -0,0 +1,101 @@
+#[allow(unused_imports)]
+use anyhow::{anyhow, bail, Context, Error, Result};
+
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+
+use rusoto_core::RusotoError;
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// How many times upload is retried if `S3BucketBuilder::retries` is not set
+pub const DEFAULT_RETRIES: u32 = 3;
+
+/// Pause before the first retry, doubled for each next one
+const RETRY_BACKOFF: std::time::Duration = std::time::Duration::from_secs(1);
+
+static UPLOAD_RETRIES: AtomicU64 = AtomicU64::new(0);
+
+/// Retries of uploads (`S3Bucket::upload` and parts of `MultipartWriter`) so far
+pub fn upload_retries() -> u64 {
+    UPLOAD_RETRIES.load(Ordering::Relaxed)
+}
+
+/// Uploads `what` by `upload` until it succeeds, fails with error which is not transient (see `is_transient`) or is
+/// retried `retries` times
+pub(crate) async fn upload<T, E, F, Fut>(
+    what: &str,
+    retries: u32,
+    mut upload: F,
+) -> Result<T, RusotoError<E>>
+where
+    E: std::error::Error + 'static,
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, RusotoError<E>>>,
+{
+    let mut retry = 0;
+    loop {
+        match upload().await {
+            Err(err) if retry < retries && is_transient(&err) => {
+                let backoff = RETRY_BACKOFF * 2u32.pow(retry);
+                retry += 1;
+                UPLOAD_RETRIES.fetch_add(1, Ordering::Relaxed);
+                warn!("failed to upload {what}: {err}, will retry in {backoff:?}");
+                tokio::time::sleep(backoff).await;
+            }
+            ret => return ret,
+        }
+    }
+}
+
+/// Network error, 5xx or 429
+fn is_transient<E>(err: &RusotoError<E>) -> bool {
+    match err {
+        RusotoError::HttpDispatch(_) => true,
+        RusotoError::Unknown(resp) => resp.status.is_server_error() || resp.status.as_u16() == 429,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rusoto_core::request::HttpDispatchError;
+    use rusoto_s3::PutObjectError;
+
+    #[tokio::test]
+    async fn test_upload() {
+        let before = upload_retries();
+        let mut attempts = 0;
+        let ret = upload("test", 3, || {
+            attempts += 1;
+            let attempt = attempts;
+            async move {
+                if attempt == 1 {
+                    Err(RusotoError::<PutObjectError>::HttpDispatch(
+                        HttpDispatchError::new("connection reset".to_owned()),
+                    ))
+                } else {
+                    Ok(attempt)
+                }
+            }
+        })
+        .await;
+        assert_eq!(ret.unwrap(), 2);
+        assert_eq!(upload_retries() - before, 1);
+
+        // not transient
+        let mut attempts = 0;
+        let ret = upload("test", 3, || {
+            attempts += 1;
+            async {
+                Err::<(), _>(RusotoError::<PutObjectError>::Validation(
+                    "bad key".to_owned(),
+                ))
+            }
+        })
+        .await;
+        assert!(ret.is_err());
+        assert_eq!(attempts, 1);
+    }
+}